@@ -0,0 +1,94 @@
+//! Runs the outbound flow control over a set of simulated links.
+//!
+//! `cargo run --example simulate [CONFIG]` where `CONFIG` is an optional
+//! airboard config file whose `flow` and `inbound` sections are used.
+
+mod sim;
+
+use airboard_server::config::Config;
+use airboard_server::Clip;
+use sim::{simulate, LinkConfig};
+
+use std::rc::Rc;
+use std::time::Duration;
+
+fn main() {
+    let config = match Config::load(std::env::args_os().nth(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let base = LinkConfig::default();
+    let links = vec![
+        ("clean", base.clone()),
+        (
+            "lossy",
+            LinkConfig {
+                loss: 0.05,
+                ..base.clone()
+            },
+        ),
+        (
+            "small mtu",
+            LinkConfig {
+                max_len: 185,
+                ..base.clone()
+            },
+        ),
+        (
+            "known mtu",
+            LinkConfig {
+                max_len: 244,
+                mtu: Some(247),
+                ..base.clone()
+            },
+        ),
+        (
+            "slow",
+            LinkConfig {
+                latency: Duration::from_millis(60),
+                bandwidth: 4_000,
+                loss: 0.02,
+                ..base.clone()
+            },
+        ),
+    ];
+    let sizes = [100, 10_000, 200_000];
+    let mut failed = false;
+    for (name, link) in &links {
+        for size in &sizes {
+            let data: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+            let clip = Rc::new(Clip::new(data, "application/octet-stream".to_owned()));
+            let res = simulate(clip, config.flow.clone(), config.inbound.clone(), link);
+            match &res.stats {
+                _ if res.rejected.is_some() => {
+                    failed = true;
+                    println!(
+                        "{:>10} {:>7} B: rejected by the client: {}",
+                        name,
+                        size,
+                        res.rejected.unwrap().name()
+                    );
+                }
+                Some(stats) if res.completed => {
+                    println!(
+                        "{:>10} {:>7} B: {} ({} dropped)",
+                        name, size, stats, res.dropped
+                    )
+                }
+                _ => {
+                    failed = true;
+                    println!(
+                        "{:>10} {:>7} B: did not complete in {:?}",
+                        name, size, res.elapsed
+                    );
+                }
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
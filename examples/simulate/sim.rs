@@ -0,0 +1,145 @@
+//! A simulated BLE link for exercising the outbound flow control without a device.
+//!
+//! The `OutSyncer` under test sends notifications into a lossy, rate limited link.
+//! The client side is played by an `InSyncer`, whose replies use the same format as
+//! the ACKs a phone writes back to the read characteristic.
+
+use rustable::gatt::AttValue;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use airboard_server::config::InboundConfig;
+use airboard_server::flow::FlowConfig;
+use airboard_server::stats::TransferStats;
+use airboard_server::{Clip, InError, InSyncer, OutSyncer};
+
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// One way latency of the link.
+    pub latency: Duration,
+    /// Link throughput in bytes per second.
+    pub bandwidth: usize,
+    /// Probability that a notification is dropped.
+    pub loss: f64,
+    /// Notifications longer than this are always dropped.
+    pub max_len: usize,
+    /// The ATT MTU reported to the syncer, `None` to make it probe for the chunk size.
+    pub mtu: Option<u16>,
+    /// Interval at which the daemon polls the syncer.
+    pub poll_interval: Duration,
+    /// The simulation gives up after this much simulated time.
+    pub time_limit: Duration,
+    pub seed: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(15),
+            bandwidth: 20_000,
+            loss: 0.0,
+            max_len: 495,
+            mtu: None,
+            poll_interval: Duration::from_millis(20),
+            time_limit: Duration::from_secs(600),
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SimResult {
+    /// Statistics of the transfer, `None` if the client never started it.
+    pub stats: Option<TransferStats>,
+    /// Simulated time until the transfer completed or the time limit was hit.
+    pub elapsed: Duration,
+    pub completed: bool,
+    pub dropped: usize,
+    /// Set if the client rejected the clip.
+    pub rejected: Option<InError>,
+}
+
+// xorshift64, good enough for loss simulation and reproducible with the seed
+struct Rng(u64);
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Transfers `clip` over a simulated link to a client with the limits of `inbound`
+/// and returns the resulting statistics.
+pub fn simulate(
+    clip: Rc<Clip>,
+    flow: FlowConfig,
+    inbound: InboundConfig,
+    link: &LinkConfig,
+) -> SimResult {
+    let start = Instant::now();
+    let tick = Duration::from_millis(1);
+    let mut rng = Rng(link.seed.max(1));
+    let mut out = OutSyncer::with_config(clip, 0, flow);
+    if let Some(mtu) = link.mtu {
+        out.set_mtu(mtu);
+    }
+    let mut client = InSyncer::with_config(Rc::new(Clip::default()), inbound);
+    let mut rejected = None;
+    // (arrival time, value) of notifications and ACKs in flight
+    let mut to_client: VecDeque<(Duration, AttValue)> = VecDeque::new();
+    let mut to_server: VecDeque<(Duration, AttValue)> = VecDeque::new();
+    let mut link_free = Duration::default();
+    let mut next_poll = Duration::default();
+    let mut dropped = 0;
+    let mut now = Duration::default();
+    while now < link.time_limit {
+        while to_client.front().is_some_and(|(t, _)| *t <= now) {
+            let (_, v) = to_client.pop_front().unwrap();
+            let (_, ack) = client.process_write(&v);
+            if let Some(r) = client.take_rejection() {
+                rejected = Some(r.error);
+            }
+            to_server.push_back((now + link.latency, ack));
+        }
+        while to_server.front().is_some_and(|(t, _)| *t <= now) {
+            let (_, ack) = to_server.pop_front().unwrap();
+            out.update_pos_at(&ack, start + now);
+        }
+        if rejected.is_some() {
+            break;
+        }
+        if let Some(stats) = out.stats() {
+            if stats.completed {
+                break;
+            }
+        }
+        if now >= next_poll {
+            next_poll = now + link.poll_interval;
+            out.indicate(start + now, |v| {
+                // notifications are serialized on the link
+                let send_time = Duration::from_secs_f64(v.len() as f64 / link.bandwidth as f64);
+                link_free = link_free.max(now) + send_time;
+                if v.len() > link.max_len || rng.next_f64() < link.loss {
+                    dropped += 1;
+                } else {
+                    to_client.push_back((link_free + link.latency, v));
+                }
+                Ok(())
+            })
+            .ok();
+        }
+        now += tick;
+    }
+    let stats = out.stats().cloned();
+    SimResult {
+        completed: stats.as_ref().is_some_and(|s| s.completed),
+        stats,
+        elapsed: now,
+        dropped,
+        rejected,
+    }
+}
@@ -0,0 +1,879 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::env::var_os;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::files::FILES_MIME;
+use crate::flow::FlowConfig;
+use crate::image::RASTER_TYPES;
+
+/// Daemon configuration, read from `~/.config/airboard/config.yaml`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub service: ServiceConfig,
+    pub flow: FlowConfig,
+    pub inbound: InboundConfig,
+    pub advert: AdvertConfig,
+    pub pairing: PairingConfig,
+    pub local: LocalConfig,
+    pub echo: EchoConfig,
+    pub text: TextConfig,
+    pub image: ImageConfig,
+    pub rich: RichConfig,
+    pub files: FilesConfig,
+    pub sensitive: SensitiveConfig,
+    pub expiry: ExpiryConfig,
+    /// Clipboard channels, each hosted as its own service. If empty a single channel
+    /// syncing the clipboard with the UUIDs of `service` is used.
+    pub channels: Vec<ChannelConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selection {
+    #[default]
+    Clipboard,
+    Primary,
+}
+
+/// Which way clips flow between the local selection and the remote devices.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Both,
+    /// Only send local changes to the remote devices.
+    Send,
+    /// Only write clips from the remote devices to the local selection.
+    Receive,
+}
+
+impl Direction {
+    pub fn sends(self) -> bool {
+        self != Direction::Receive
+    }
+    pub fn receives(self) -> bool {
+        self != Direction::Send
+    }
+}
+
+/// A clipboard synced separately from the others.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    /// Name of the channel. The channel with an empty name uses the UUIDs of `service`,
+    /// the UUIDs of the others are derived from `namespace`.
+    pub name: String,
+    pub selection: Selection,
+    pub direction: Direction,
+    /// How long the selection must stay unchanged before it is sent, so that
+    /// drag-selecting text doesn't start a transfer for every change.
+    /// Defaults to `local.settle_ms`, and at least 500 for the primary selection.
+    pub debounce_ms: Option<u64>,
+    /// Namespace of the channel's UUIDs, `<service namespace or "airboard">/<name>` if unset.
+    pub namespace: Option<String>,
+    /// Limits on clips received on this channel, the top level `inbound` if unset.
+    pub inbound: Option<InboundConfig>,
+    /// Only serve the channel while one of these devices is connected, always if empty.
+    /// This is not access control: BlueZ doesn't tell which device made a request,
+    /// so any device can use the channel while one of these is connected.
+    pub active_with_devices: Vec<String>,
+}
+
+impl ChannelConfig {
+    /// Returns whether the channel is served while `peers` are connected.
+    pub fn active_with(&self, peers: &[&str]) -> bool {
+        self.active_with_devices.is_empty()
+            || peers.iter().any(|p| {
+                self.active_with_devices
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(p))
+            })
+    }
+}
+
+/// What happens to a transfer in progress when the local clipboard changes.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InFlightPolicy {
+    /// Abort the transfer and start sending the new clip.
+    Abort,
+    /// Finish the transfer, then send the latest clip.
+    Finish,
+    /// Finish the transfer, then send every clip in the order they were copied.
+    Queue,
+}
+
+/// Handling of changes to the local clipboard.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocalConfig {
+    /// How long the clipboard must stay unchanged before it is sent, so that
+    /// copying several things in quick succession only sends the last.
+    pub settle_ms: u64,
+    pub in_flight: InFlightPolicy,
+    /// Maximum number of clips waiting with the `queue` policy, the oldest are dropped.
+    pub max_queue: usize,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            settle_ms: 0,
+            in_flight: InFlightPolicy::Abort,
+            max_queue: 8,
+        }
+    }
+}
+
+/// Normalisation of text clips, which are always converted to UTF-8.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TextConfig {
+    /// Convert CRLF and CR line endings to LF.
+    pub normalise_line_endings: bool,
+    /// Strip whitespace at the end of lines and trailing empty lines.
+    pub strip_trailing_whitespace: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn mime(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+    /// Name of the format for ImageMagick.
+    pub fn magick(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Transcoding of image clips with ImageMagick.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ImageConfig {
+    /// Re-encode large outbound images before sending them.
+    pub transcode: bool,
+    /// Images larger than this many bytes are re-encoded.
+    pub max_bytes: usize,
+    /// Images wider or taller than this are downscaled, 0 for no limit.
+    pub max_dimension: u32,
+    pub format: ImageFormat,
+    /// Quality of lossy formats, from 1 to 100.
+    pub quality: u8,
+    /// Image types accepted by the local clipboard, others received are converted to PNG.
+    pub local_types: Vec<String>,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            transcode: false,
+            max_bytes: 256 * 1024,
+            max_dimension: 1920,
+            format: ImageFormat::Jpeg,
+            quality: 80,
+            local_types: vec!["image/png".to_owned(), "image/jpeg".to_owned()],
+        }
+    }
+}
+
+/// Handling of rich text clips.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RichConfig {
+    /// Send HTML clips as sanitised HTML instead of converting them to plain text,
+    /// for devices that can paste HTML.
+    pub keep_html: bool,
+    /// Read RTF clips, converting them to plain text, when no text is offered.
+    pub rtf: bool,
+}
+
+impl Default for RichConfig {
+    fn default() -> Self {
+        Self {
+            keep_html: false,
+            rtf: true,
+        }
+    }
+}
+
+/// Sending copied files and saving received ones.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FilesConfig {
+    /// Send the contents of files copied in a file manager.
+    pub send: bool,
+    /// Save files received from devices and put them on the clipboard.
+    pub receive: bool,
+    /// Where received files are saved, `~/Downloads/airboard` if unset.
+    pub download_dir: Option<PathBuf>,
+    /// Largest total size of the files sent at once.
+    pub max_bytes: usize,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            send: false,
+            receive: false,
+            download_dir: None,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl FilesConfig {
+    pub fn download_dir(&self) -> PathBuf {
+        match &self.download_dir {
+            Some(dir) => dir.clone(),
+            None => {
+                let mut path: PathBuf = var_os("HOME").unwrap_or_default().into();
+                path.push("Downloads/airboard");
+                path
+            }
+        }
+    }
+}
+
+/// Clips that are never sent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SensitiveConfig {
+    /// Clipboard types set by password managers to mark secrets.
+    pub hints: Vec<String>,
+    /// Regular expressions matched against text clips.
+    pub patterns: Vec<String>,
+    /// Don't send text containing numbers grouped like a card number of a known issuer
+    /// that pass the card number checksum.
+    pub card_numbers: bool,
+    /// Applications whose copies are not sent, compared with the output of `active_app_command`.
+    pub app_blacklist: Vec<String>,
+    /// Shell command printing the id of the focused application, as the clipboard
+    /// doesn't tell where a clip came from. For sway:
+    /// `swaymsg -t get_tree | jq -r '.. | select(.focused? == true) | .app_id'`
+    pub active_app_command: Option<String>,
+}
+
+impl Default for SensitiveConfig {
+    fn default() -> Self {
+        Self {
+            hints: vec!["x-kde-passwordManagerHint".to_owned()],
+            patterns: vec![
+                "-----BEGIN [A-Z ]*PRIVATE KEY-----".to_owned(),
+                r"\bAKIA[0-9A-Z]{16}\b".to_owned(),
+                r"\bgh[pousr]_[A-Za-z0-9]{36,}\b".to_owned(),
+            ],
+            card_numbers: false,
+            app_blacklist: Vec::new(),
+            active_app_command: None,
+        }
+    }
+}
+
+/// Expiry of synced clips.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    /// Seconds after which a clip is no longer served and clients are told to
+    /// clear it, 0 to keep clips forever.
+    pub ttl_secs: u64,
+    /// Also clear the local clipboard if it still holds a clip received from a device.
+    pub clear_local: bool,
+}
+
+impl ExpiryConfig {
+    pub fn ttl(&self) -> Option<Duration> {
+        if self.ttl_secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.ttl_secs))
+        }
+    }
+}
+
+/// Suppression of received clips read back from the local clipboard.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EchoConfig {
+    /// How long a received clip is remembered, 0 to disable the suppression.
+    pub ttl_secs: u64,
+    /// Number of clips remembered for each device.
+    pub per_origin: usize,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 30,
+            per_origin: 4,
+        }
+    }
+}
+
+/// Pairing through the daemon's own BlueZ agent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PairingConfig {
+    /// Register an agent while a pairing window is open, instead of relying on the one
+    /// of the desktop session. Devices that pair during the window are trusted.
+    pub agent: bool,
+    /// IO capability of the agent, `DisplayOnly` makes the other device enter the passkey.
+    pub capability: String,
+    pub window_minutes: u32,
+    /// Show passkeys with `notify-send` as well as on the terminal.
+    pub notify: bool,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self {
+            agent: false,
+            capability: "DisplayOnly".to_owned(),
+            window_minutes: 2,
+            notify: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvertMode {
+    /// Advertise whenever no client is connected.
+    Always,
+    /// Advertise only for `window_minutes` after `airboard advertise`.
+    Window,
+}
+
+/// Parameters of the BLE advertisement.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdvertConfig {
+    pub mode: AdvertMode,
+    pub window_minutes: u32,
+    /// Stop advertising while a client is subscribed to clips of one of the channels.
+    pub stop_when_connected: bool,
+    /// Make the adapter discoverable by any device.
+    /// If disabled only bonded devices that already know the address will find it.
+    pub discoverable: bool,
+    /// Seconds the advertisement is shown for in each rotation.
+    pub duration: u16,
+    /// Seconds before BlueZ removes the advertisement, it is restarted after.
+    pub timeout: u16,
+}
+
+impl Default for AdvertConfig {
+    fn default() -> Self {
+        Self {
+            mode: AdvertMode::Always,
+            window_minutes: 5,
+            stop_when_connected: true,
+            discoverable: true,
+            duration: 2,
+            timeout: u16::MAX,
+        }
+    }
+}
+
+/// Identity of the GATT service, so that several instances can run side by side.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    /// Name of the instance. A non-empty name is appended to the bus name and
+    /// separates the handle cache, control socket and state files.
+    pub instance: String,
+    /// Derives all UUIDs from this string instead of using the ones below.
+    pub namespace: Option<String>,
+    /// D-Bus name, derived from `instance` if unset. The D-Bus policy must allow owning it.
+    pub bus_name: Option<String>,
+    pub service_uuid: String,
+    pub read_uuid: String,
+    pub write_uuid: String,
+    pub ver_uuid: String,
+    pub len_uuid: String,
+    pub mime_uuid: String,
+    pub hash_uuid: String,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            instance: String::new(),
+            namespace: None,
+            bus_name: None,
+            service_uuid: "4981333e-2d59-43b2-8dc3-8fedee1472c5".to_owned(),
+            read_uuid: "07178017-1879-451b-9bb5-3ff13bb85b70".to_owned(),
+            write_uuid: "07178017-1879-451b-9bb5-3ff13bb85b71".to_owned(),
+            ver_uuid: "b05778f1-5a88-46a3-b6c8-2d154d629910".to_owned(),
+            len_uuid: "b05778f1-5a88-46a3-b6c8-2d154d629911".to_owned(),
+            mime_uuid: "b05778f1-5a88-46a3-b6c8-2d154d629912".to_owned(),
+            hash_uuid: "b05778f1-5a88-46a3-b6c8-2d154d629913".to_owned(),
+        }
+    }
+}
+
+/// The UUIDs of the service, its characteristics and descriptors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceUuids {
+    pub service: String,
+    pub read: String,
+    pub write: String,
+    pub ver: String,
+    pub len: String,
+    pub mime: String,
+    pub hash: String,
+}
+
+/// Derives a UUID for `role` from `namespace`, in the format of a version 8 UUID.
+pub fn derive_uuid(namespace: &str, role: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(namespace.as_bytes());
+    hasher.update(b"/");
+    hasher.update(role.as_bytes());
+    let mut b = [0; 16];
+    b.copy_from_slice(&hasher.finalize()[..16]);
+    b[6] = (b[6] & 0x0f) | 0x80;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|x| format!("{:02x}", x)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn valid_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn valid_uuid(uuid: &str) -> bool {
+    uuid.len() == 36
+        && uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+impl ServiceConfig {
+    /// Returns the UUIDs of the service hosting `channel`.
+    pub fn channel_uuids(&self, channel: &ChannelConfig) -> ServiceUuids {
+        if channel.name.is_empty() {
+            return self.uuids();
+        }
+        let ns = match &channel.namespace {
+            Some(ns) => ns.clone(),
+            None => format!(
+                "{}/{}",
+                self.namespace.as_deref().unwrap_or("airboard"),
+                channel.name
+            ),
+        };
+        ServiceConfig {
+            namespace: Some(ns),
+            ..Default::default()
+        }
+        .uuids()
+    }
+    pub fn uuids(&self) -> ServiceUuids {
+        match &self.namespace {
+            Some(ns) => ServiceUuids {
+                service: derive_uuid(ns, "service"),
+                read: derive_uuid(ns, "read"),
+                write: derive_uuid(ns, "write"),
+                ver: derive_uuid(ns, "ver"),
+                len: derive_uuid(ns, "len"),
+                mime: derive_uuid(ns, "mime"),
+                hash: derive_uuid(ns, "hash"),
+            },
+            None => ServiceUuids {
+                service: self.service_uuid.to_ascii_lowercase(),
+                read: self.read_uuid.to_ascii_lowercase(),
+                write: self.write_uuid.to_ascii_lowercase(),
+                ver: self.ver_uuid.to_ascii_lowercase(),
+                len: self.len_uuid.to_ascii_lowercase(),
+                mime: self.mime_uuid.to_ascii_lowercase(),
+                hash: self.hash_uuid.to_ascii_lowercase(),
+            },
+        }
+    }
+    pub fn bus_name(&self) -> String {
+        match &self.bus_name {
+            Some(name) => name.clone(),
+            None if self.instance.is_empty() => "io.maves.airboard".to_owned(),
+            None => format!("io.maves.airboard.{}", self.instance),
+        }
+    }
+    /// Returns `base` with the instance name appended, for per instance files.
+    pub fn instance_file(&self, base: &str) -> String {
+        if self.instance.is_empty() {
+            base.to_owned()
+        } else {
+            format!("{}-{}", base, self.instance)
+        }
+    }
+    fn validate(&self) -> Result<(), String> {
+        if !valid_name(&self.instance) {
+            return Err(format!(
+                "instance {:?} may only contain letters, digits and '_' and must not start with a digit",
+                self.instance
+            ));
+        }
+        if self.namespace.is_none() {
+            let u = self.uuids();
+            for uuid in [
+                &u.service, &u.read, &u.write, &u.ver, &u.len, &u.mime, &u.hash,
+            ]
+            .iter()
+            {
+                if !valid_uuid(uuid) {
+                    return Err(format!("invalid UUID {:?}", uuid));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limits on clips received from clients.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InboundConfig {
+    /// Largest clip in bytes that will be accepted.
+    pub max_len: u32,
+    /// Accepted mime types, all types are accepted if empty.
+    /// A type ending in `/*` accepts all subtypes. Accepting `image/png` accepts all
+    /// raster images, as those not in `image.local_types` are converted to PNG.
+    pub mimes: Vec<String>,
+    /// Number of consecutive failed transfers from a device before warning about it.
+    pub error_threshold: u32,
+}
+
+impl Default for InboundConfig {
+    fn default() -> Self {
+        Self {
+            max_len: 64 * 1024 * 1024,
+            mimes: Vec::new(),
+            error_threshold: 3,
+        }
+    }
+}
+
+impl InboundConfig {
+    pub fn accepts_mime(&self, mime: &str) -> bool {
+        if self.mimes.is_empty() {
+            return true;
+        }
+        // ignore parameters such as the charset
+        let base = mime.split(';').next().unwrap_or("").trim();
+        self.mimes.iter().any(|m| match m.strip_suffix("/*") {
+            Some(prefix) => base
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/')),
+            None => m.eq_ignore_ascii_case(base) || m.eq_ignore_ascii_case(mime),
+        })
+    }
+}
+
+pub fn default_config_path() -> PathBuf {
+    let mut path: PathBuf = var_os("HOME").unwrap_or_default().into();
+    path.push(".config/airboard/config.yaml");
+    path
+}
+
+impl Config {
+    /// Loads the configuration from `path` or the default location.
+    ///
+    /// A missing file results in the default configuration.
+    pub fn load<T: AsRef<Path>>(path: Option<T>) -> std::io::Result<Self> {
+        let path = match path {
+            Some(p) => p.as_ref().to_path_buf(),
+            None => default_config_path(),
+        };
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let config: Self = serde_yaml::from_reader(file)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        config
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+    fn validate(&self) -> Result<(), String> {
+        self.service.validate()?;
+        for pattern in &self.sensitive.patterns {
+            if let Err(e) = regex::Regex::new(pattern) {
+                return Err(format!("invalid sensitive pattern {:?}: {}", pattern, e));
+            }
+        }
+        let mut names = Vec::new();
+        for ch in &self.channels {
+            if !valid_name(&ch.name) {
+                return Err(format!(
+                    "channel name {:?} may only contain letters, digits and '_'",
+                    ch.name
+                ));
+            }
+            if names.contains(&&ch.name) {
+                return Err(format!("duplicate channel {:?}", ch.name));
+            }
+            names.push(&ch.name);
+        }
+        Ok(())
+    }
+    /// Returns the configured channels, or the default channel if there are none.
+    pub fn channels(&self) -> Vec<ChannelConfig> {
+        if self.channels.is_empty() {
+            vec![ChannelConfig::default()]
+        } else {
+            self.channels.clone()
+        }
+    }
+    /// Returns how long local changes to `channel` must settle before they are sent.
+    pub fn settle_for(&self, channel: &ChannelConfig) -> Duration {
+        let ms = match (channel.debounce_ms, channel.selection) {
+            (Some(ms), _) => ms,
+            (None, Selection::Primary) => self.local.settle_ms.max(500),
+            (None, Selection::Clipboard) => self.local.settle_ms,
+        };
+        Duration::from_millis(ms)
+    }
+    /// Returns the inbound limits that apply to `channel`.
+    pub fn inbound_for(&self, channel: &ChannelConfig) -> InboundConfig {
+        let mut inbound = channel
+            .inbound
+            .clone()
+            .unwrap_or_else(|| self.inbound.clone());
+        if self.files.receive && !inbound.accepts_mime(FILES_MIME) {
+            inbound.mimes.push(FILES_MIME.to_owned());
+        }
+        // raster images the local clipboard doesn't take are converted to PNG
+        if inbound.accepts_mime(ImageFormat::Png.mime()) {
+            for (mime, _) in RASTER_TYPES {
+                if !inbound.accepts_mime(mime) {
+                    inbound.mimes.push(mime.to_string());
+                }
+            }
+        }
+        inbound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inbound_accepts_all_by_default() {
+        let inbound = InboundConfig::default();
+        assert!(inbound.accepts_mime("application/octet-stream"));
+        assert!(inbound.accepts_mime("text/plain;charset=utf-8"));
+    }
+
+    #[test]
+    fn inbound_mime_patterns() {
+        let inbound = InboundConfig {
+            mimes: vec!["text/*".to_owned(), "image/png".to_owned()],
+            ..InboundConfig::default()
+        };
+        assert!(inbound.accepts_mime("text/plain"));
+        assert!(inbound.accepts_mime("text/html; charset=utf-8"));
+        assert!(inbound.accepts_mime("IMAGE/PNG"));
+        assert!(!inbound.accepts_mime("image/jpeg"));
+        assert!(!inbound.accepts_mime("textual/plain"));
+        assert!(!inbound.accepts_mime("application/octet-stream"));
+    }
+
+    #[test]
+    fn derived_uuids() {
+        let uuid = derive_uuid("airboard", "service");
+        assert!(valid_uuid(&uuid));
+        assert_eq!(uuid, derive_uuid("airboard", "service"));
+        assert_ne!(uuid, derive_uuid("airboard", "read"));
+        assert_ne!(uuid, derive_uuid("airboard2", "service"));
+        // version 8, RFC 4122 variant
+        assert_eq!(&uuid[14..15], "8");
+        assert!("89ab".contains(&uuid[19..20]));
+    }
+
+    #[test]
+    fn uuid_format() {
+        assert!(valid_uuid("4981333e-2d59-43b2-8dc3-8fedee1472c5"));
+        assert!(valid_uuid("4981333E-2D59-43B2-8DC3-8FEDEE1472C5"));
+        assert!(!valid_uuid("4981333e2d5943b28dc38fedee1472c5"));
+        assert!(!valid_uuid("4981333e-2d59-43b2-8dc3-8fedee1472cg"));
+        assert!(!valid_uuid("4981333e-2d59-43b2-8dc3-8fedee1472c"));
+    }
+
+    #[test]
+    fn service_identity() {
+        let mut service = ServiceConfig::default();
+        assert_eq!(service.bus_name(), "io.maves.airboard");
+        assert_eq!(service.instance_file("handles"), "handles");
+        assert_eq!(
+            service.uuids().service,
+            "4981333e-2d59-43b2-8dc3-8fedee1472c5"
+        );
+        assert!(service.validate().is_ok());
+
+        service.instance = "work".to_owned();
+        assert_eq!(service.bus_name(), "io.maves.airboard.work");
+        assert_eq!(service.instance_file("handles"), "handles-work");
+        service.bus_name = Some("org.example.Clipboard".to_owned());
+        assert_eq!(service.bus_name(), "org.example.Clipboard");
+
+        service.namespace = Some("work".to_owned());
+        let uuids = service.uuids();
+        assert_eq!(uuids.service, derive_uuid("work", "service"));
+        assert_eq!(uuids.hash, derive_uuid("work", "hash"));
+    }
+
+    #[test]
+    fn service_validated() {
+        let service = ServiceConfig {
+            instance: "1st".to_owned(),
+            ..ServiceConfig::default()
+        };
+        assert!(service.validate().is_err());
+        let service = ServiceConfig {
+            instance: "a.b".to_owned(),
+            ..ServiceConfig::default()
+        };
+        assert!(service.validate().is_err());
+        let service = ServiceConfig {
+            read_uuid: "07178017".to_owned(),
+            ..ServiceConfig::default()
+        };
+        assert!(service.validate().is_err());
+        // the configured UUIDs are ignored with a namespace
+        let service = ServiceConfig {
+            read_uuid: "07178017".to_owned(),
+            namespace: Some("airboard".to_owned()),
+            ..ServiceConfig::default()
+        };
+        assert!(service.validate().is_ok());
+    }
+
+    fn channel(name: &str) -> ChannelConfig {
+        ChannelConfig {
+            name: name.to_owned(),
+            ..ChannelConfig::default()
+        }
+    }
+
+    #[test]
+    fn channels_validated() {
+        let mut config = Config::default();
+        assert_eq!(config.channels().len(), 1);
+        config.channels = vec![channel(""), channel("work")];
+        assert!(config.validate().is_ok());
+        config.channels.push(channel("work"));
+        assert_eq!(
+            config.validate(),
+            Err("duplicate channel \"work\"".to_owned())
+        );
+        config.channels = vec![channel("my-work")];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn channel_uuids() {
+        let service = ServiceConfig::default();
+        assert_eq!(service.channel_uuids(&channel("")), service.uuids());
+        let work = service.channel_uuids(&channel("work"));
+        assert_eq!(work.service, derive_uuid("airboard/work", "service"));
+        assert_ne!(work, service.channel_uuids(&channel("home")));
+        let mut named = channel("work");
+        named.namespace = Some("shared".to_owned());
+        assert_eq!(
+            service.channel_uuids(&named).read,
+            derive_uuid("shared", "read")
+        );
+    }
+
+    #[test]
+    fn channel_devices() {
+        let mut ch = channel("work");
+        assert!(ch.active_with(&[]));
+        ch.active_with_devices = vec!["AA:BB:CC:DD:EE:FF".to_owned()];
+        assert!(!ch.active_with(&[]));
+        assert!(!ch.active_with(&["11:22:33:44:55:66"]));
+        assert!(ch.active_with(&["11:22:33:44:55:66", "aa:bb:cc:dd:ee:ff"]));
+    }
+
+    #[test]
+    fn channel_inbound() {
+        let mut config = Config::default();
+        config.inbound.max_len = 1000;
+        let mut ch = channel("work");
+        assert_eq!(config.inbound_for(&ch).max_len, 1000);
+        ch.inbound = Some(InboundConfig {
+            max_len: 10,
+            mimes: vec!["text/*".to_owned()],
+            ..InboundConfig::default()
+        });
+        let inbound = config.inbound_for(&ch);
+        assert_eq!(inbound.max_len, 10);
+        assert!(!inbound.accepts_mime(FILES_MIME));
+        config.files.receive = true;
+        assert!(config.inbound_for(&ch).accepts_mime(FILES_MIME));
+    }
+
+    #[test]
+    fn channel_settle_time() {
+        let mut config = Config::default();
+        config.local.settle_ms = 200;
+        let mut ch = channel("");
+        assert_eq!(config.settle_for(&ch), Duration::from_millis(200));
+        ch.selection = Selection::Primary;
+        assert_eq!(config.settle_for(&ch), Duration::from_millis(500));
+        ch.debounce_ms = Some(50);
+        assert_eq!(config.settle_for(&ch), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn converted_images_accepted() {
+        let mut config = Config::default();
+        config.inbound.mimes = vec!["text/*".to_owned()];
+        let ch = channel("");
+        assert!(!config.inbound_for(&ch).accepts_mime("image/webp"));
+        config.inbound.mimes.push("image/png".to_owned());
+        let inbound = config.inbound_for(&ch);
+        assert!(inbound.accepts_mime("image/webp"));
+        assert!(inbound.accepts_mime("image/bmp"));
+        assert!(!inbound.accepts_mime("image/svg+xml"));
+        assert!(!inbound.accepts_mime("application/pdf"));
+    }
+
+    #[test]
+    fn expiry_ttl() {
+        let mut expiry = ExpiryConfig::default();
+        assert_eq!(expiry.ttl(), None);
+        expiry.ttl_secs = 90;
+        assert_eq!(expiry.ttl(), Some(Duration::from_secs(90)));
+    }
+}
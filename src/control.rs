@@ -0,0 +1,176 @@
+use std::env::var_os;
+use std::fs::{DirBuilder, Permissions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default location of the control socket used by the `status` command.
+/// `name` is `airboard`, or with the instance name for other instances.
+///
+/// The socket is put in `XDG_RUNTIME_DIR`. Without it a private directory
+/// is created in the temporary directory.
+pub fn default_socket_path(name: &str) -> std::io::Result<PathBuf> {
+    let mut path: PathBuf = match var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => dir.into(),
+        None => private_dir(&std::env::temp_dir())?,
+    };
+    path.push(format!("{}.sock", name));
+    Ok(path)
+}
+
+/// Creates `airboard-<uid>` in `parent` only accessible to the current user.
+/// An existing directory is only used if it belongs to us and isn't accessible to others.
+fn private_dir(parent: &Path) -> std::io::Result<PathBuf> {
+    let uid = nix::unistd::getuid().as_raw();
+    let dir = parent.join(format!("airboard-{}", uid));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => return Ok(dir),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e),
+    }
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{:?} is not a private directory of the current user", dir),
+        ));
+    }
+    Ok(dir)
+}
+
+/// A nonblocking unix socket accepting one line commands from the command line client.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn bind<T: AsRef<Path>>(path: T) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // remove a stale socket from a previous run, but nothing that isn't ours
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            let ours = meta.uid() == nix::unistd::getuid().as_raw();
+            if meta.file_type().is_socket() && ours && UnixStream::connect(&path).is_err() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path })
+    }
+    /// Accepts all pending connections, replying to each command with the output of `handler`.
+    pub fn process<F: FnMut(&str) -> String>(&mut self, mut handler: F) -> std::io::Result<()> {
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+            let mut cmd = String::new();
+            if stream.read_to_string(&mut cmd).is_err() {
+                continue;
+            }
+            let reply = handler(cmd.trim());
+            stream.write_all(reply.as_bytes()).ok();
+        }
+    }
+}
+
+impl AsRawFd for ControlServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Sends `cmd` to a running daemon and returns its reply.
+/// Gives up if the daemon doesn't reply within five seconds.
+pub fn send_command<T: AsRef<Path>>(path: T, cmd: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(cmd.as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_round_trip() {
+        let path = std::env::temp_dir().join(format!("airboard-test-{}.sock", std::process::id()));
+        let mut server = ControlServer::bind(&path).unwrap();
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || send_command(client_path, "status\n").unwrap());
+        let mut cmds = Vec::new();
+        for _ in 0..200 {
+            if !cmds.is_empty() {
+                break;
+            }
+            server
+                .process(|cmd| {
+                    cmds.push(cmd.to_owned());
+                    format!("got {}", cmd)
+                })
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(client.join().unwrap(), "got status");
+        assert_eq!(cmds, ["status"]);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unresponsive_daemon_times_out() {
+        let path = std::env::temp_dir().join(format!("airboard-idle-{}.sock", std::process::id()));
+        // bound, but never accepting
+        let _server = ControlServer::bind(&path).unwrap();
+        let start = std::time::Instant::now();
+        assert!(send_command(&path, "status").is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn socket_private() {
+        let path = std::env::temp_dir().join(format!("airboard-mode-{}.sock", std::process::id()));
+        // a stale socket is replaced, but other files are left alone
+        drop(UnixListener::bind(&path).unwrap());
+        let server = ControlServer::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(server);
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(ControlServer::bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn private_dir_checked() {
+        let parent = std::env::temp_dir().join(format!("airboard-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+        let dir = private_dir(&parent).unwrap();
+        assert_eq!(std::fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        // reused while it stays private
+        assert_eq!(private_dir(&parent).unwrap(), dir);
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        assert!(private_dir(&parent).is_err());
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! Suppression of echoes, received clips read back from the local clipboard.
+//!
+//! The clipboard tools may re-encode a clip when it is written, so besides exact matches
+//! text is compared after decoding and images by their pixels.
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::image;
+use crate::text;
+use crate::Clip;
+
+struct Received {
+    origin: String,
+    at: Instant,
+    clip: Rc<Clip>,
+    /// Pixel signature, computed the first time an image is compared with it.
+    signature: Option<Option<String>>,
+}
+
+pub struct EchoFilter {
+    recent: Vec<Received>,
+    ttl: Duration,
+    per_origin: usize,
+}
+
+impl EchoFilter {
+    /// Remembers up to `per_origin` clips from each origin for `ttl`.
+    pub fn new(ttl: Duration, per_origin: usize) -> Self {
+        Self {
+            recent: Vec::new(),
+            ttl,
+            per_origin,
+        }
+    }
+    /// Records a clip received from `origin` and written to the local clipboard.
+    pub fn remember(&mut self, origin: &str, clip: Rc<Clip>, now: Instant) {
+        self.expire(now);
+        if self.per_origin == 0 {
+            return;
+        }
+        let count = self.recent.iter().filter(|r| r.origin == origin).count();
+        if count >= self.per_origin {
+            let oldest = self.recent.iter().position(|r| r.origin == origin).unwrap();
+            self.recent.remove(oldest);
+        }
+        self.recent.push(Received {
+            origin: origin.to_owned(),
+            at: now,
+            clip,
+            signature: None,
+        });
+    }
+    /// Returns the origin of the received clip `clip` is an echo of, if any.
+    pub fn echo_of(&mut self, clip: &Clip, now: Instant) -> Option<&str> {
+        self.expire(now);
+        let text = text::comparable(clip.data(), clip.mime());
+        let mut signature = None;
+        let idx = self.recent.iter_mut().rposition(|r| {
+            if *r.clip == *clip {
+                return true;
+            }
+            if let Some(text) = &text {
+                return text::comparable(r.clip.data(), r.clip.mime()).as_ref() == Some(text);
+            }
+            if !is_image(clip.mime()) || !is_image(r.clip.mime()) {
+                return false;
+            }
+            let received = &r.clip;
+            let theirs = r
+                .signature
+                .get_or_insert_with(|| pixel_signature(received))
+                .clone();
+            let ours = signature.get_or_insert_with(|| pixel_signature(clip));
+            theirs.is_some() && theirs == *ours
+        })?;
+        Some(&self.recent[idx].origin)
+    }
+    /// Forgets every received clip, once the local clipboard has moved on a clip
+    /// copied again is no longer an echo.
+    pub fn clear(&mut self) {
+        self.recent.clear();
+    }
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.recent
+            .retain(|r| now.saturating_duration_since(r.at) < ttl);
+    }
+}
+
+fn is_image(mime: &str) -> bool {
+    mime.starts_with("image/")
+}
+
+/// Hashes the decoded pixels of an image with ImageMagick, `None` if it can't be decoded.
+fn pixel_signature(clip: &Clip) -> Option<String> {
+    let input = format!("{}:-", image::coder(clip.mime())?);
+    let out = image::magick("identify", &["-format", "%#", &input], clip.data()).ok()?;
+    let sig = String::from_utf8(out).ok()?;
+    Some(sig.trim().to_owned()).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(data: &[u8], mime: &str) -> Rc<Clip> {
+        Rc::new(Clip::new(data.to_vec(), mime.to_owned()))
+    }
+
+    #[test]
+    fn exact_echo() {
+        let now = Instant::now();
+        let mut filter = EchoFilter::new(Duration::from_secs(10), 2);
+        let png = clip(b"\x89PNG not really", "image/png");
+        filter.remember("phone", png.clone(), now);
+        assert_eq!(filter.echo_of(&png, now), Some("phone"));
+        let other = clip(b"\x89PNG something else", "application/octet-stream");
+        assert_eq!(filter.echo_of(&other, now), None);
+    }
+
+    #[test]
+    fn reencoded_text_echo() {
+        let now = Instant::now();
+        let mut filter = EchoFilter::new(Duration::from_secs(10), 2);
+        filter.remember("phone", clip(b"hello\r\nworld\r\n", "text/plain"), now);
+        let utf16 = clip(
+            b"\xff\xfeh\0e\0l\0l\0o\0\n\0w\0o\0r\0l\0d\0",
+            "text/plain;charset=utf-16",
+        );
+        assert_eq!(filter.echo_of(&utf16, now), Some("phone"));
+        let string = clip(b"hello\nworld", "UTF8_STRING");
+        assert_eq!(filter.echo_of(&string, now), Some("phone"));
+        let changed = clip(b"hello\nworld!", "text/plain");
+        assert_eq!(filter.echo_of(&changed, now), None);
+    }
+
+    #[test]
+    fn clips_expire() {
+        let now = Instant::now();
+        let mut filter = EchoFilter::new(Duration::from_secs(10), 2);
+        let text = clip(b"secret", "text/plain");
+        filter.remember("phone", text.clone(), now);
+        assert!(filter
+            .echo_of(&text, now + Duration::from_secs(9))
+            .is_some());
+        assert!(filter
+            .echo_of(&text, now + Duration::from_secs(10))
+            .is_none());
+
+        filter.remember("phone", text.clone(), now);
+        filter.clear();
+        assert!(filter.echo_of(&text, now).is_none());
+    }
+
+    #[test]
+    fn limited_per_origin() {
+        let now = Instant::now();
+        let mut filter = EchoFilter::new(Duration::from_secs(10), 2);
+        let clips: Vec<_> = (0..3)
+            .map(|i| clip(format!("clip {}", i).as_bytes(), "text/plain"))
+            .collect();
+        for c in &clips {
+            filter.remember("phone", c.clone(), now);
+        }
+        filter.remember("tablet", clips[0].clone(), now);
+        assert_eq!(filter.echo_of(&clips[0], now), Some("tablet"));
+        assert_eq!(filter.echo_of(&clips[1], now), Some("phone"));
+        assert_eq!(filter.echo_of(&clips[2], now), Some("phone"));
+        filter.remember("phone", clips[0].clone(), now);
+        assert!(filter.echo_of(&clips[1], now).is_none());
+
+        let mut disabled = EchoFilter::new(Duration::from_secs(10), 0);
+        disabled.remember("phone", clips[0].clone(), now);
+        assert!(disabled.echo_of(&clips[0], now).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use rustable::Error as BLEError;
+
+use std::fmt;
+use std::path::Path;
+
+// exit codes from sysexits.h
+const EX_UNAVAILABLE: i32 = 69;
+const EX_SOFTWARE: i32 = 70;
+const EX_OSERR: i32 = 71;
+const EX_NOPERM: i32 = 77;
+const EX_CONFIG: i32 = 78;
+
+/// Errors that prevent the daemon from starting.
+#[derive(Debug)]
+pub enum DaemonError {
+    Config(std::io::Error),
+    NoHome,
+    Hostname(String),
+    BluetoothdNotRunning,
+    NoAdapter,
+    Rfkill,
+    PermissionDenied(String),
+    /// Registering the application failed even with fresh handles.
+    Registration(String),
+    /// Any other error from BlueZ, with the step that failed.
+    Bluetooth(&'static str, String),
+}
+
+impl DaemonError {
+    /// Classifies an error returned by BlueZ while performing `step`.
+    pub fn from_bluez(step: &'static str, err: &BLEError) -> Self {
+        let msg = format!("{:?}", err);
+        if msg.contains("ServiceUnknown") || msg.contains("NameHasNoOwner") {
+            DaemonError::BluetoothdNotRunning
+        } else if msg.contains("AccessDenied") || msg.contains("NotPermitted") {
+            DaemonError::PermissionDenied(msg)
+        } else if rfkill_blocked() {
+            DaemonError::Rfkill
+        } else if !adapter_present() {
+            DaemonError::NoAdapter
+        } else {
+            DaemonError::Bluetooth(step, msg)
+        }
+    }
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DaemonError::Config(_) | DaemonError::NoHome => EX_CONFIG,
+            DaemonError::Hostname(_) => EX_OSERR,
+            DaemonError::BluetoothdNotRunning | DaemonError::NoAdapter | DaemonError::Rfkill => {
+                EX_UNAVAILABLE
+            }
+            DaemonError::PermissionDenied(_) => EX_NOPERM,
+            DaemonError::Registration(_) | DaemonError::Bluetooth(_, _) => EX_SOFTWARE,
+        }
+    }
+}
+
+impl fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DaemonError::Config(e) => write!(f, "failed to load config: {}", e),
+            DaemonError::NoHome => write!(f, "HOME is not set, cannot locate the config directory"),
+            DaemonError::Hostname(e) => write!(
+                f,
+                "failed to get the device hostname ({}), pass one with --hostname",
+                e
+            ),
+            DaemonError::BluetoothdNotRunning => write!(
+                f,
+                "bluetoothd is not running, start it with `systemctl start bluetooth`"
+            ),
+            DaemonError::NoAdapter => write!(f, "no bluetooth adapter found (hci0)"),
+            DaemonError::Rfkill => write!(
+                f,
+                "the bluetooth adapter is blocked by rfkill, unblock it with `rfkill unblock bluetooth`"
+            ),
+            DaemonError::PermissionDenied(e) => write!(
+                f,
+                "permission denied on D-Bus ({}), check the bluetooth D-Bus policy for this user",
+                e
+            ),
+            DaemonError::Registration(e) => write!(
+                f,
+                "BlueZ refused to register the GATT application ({}), \
+                 check that bluetoothd runs with --experimental",
+                e
+            ),
+            DaemonError::Bluetooth(step, e) => write!(f, "failed to {}: {}", step, e),
+        }
+    }
+}
+
+impl std::error::Error for DaemonError {}
+
+/// Returns `true` while the adapter exists, it disappears if it is unplugged.
+pub fn adapter_present() -> bool {
+    Path::new("/sys/class/bluetooth/hci0").exists()
+}
+
+/// Returns `true` if a bluetooth rfkill switch is soft or hard blocked.
+pub fn rfkill_blocked() -> bool {
+    let dir = match std::fs::read_dir("/sys/class/rfkill") {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
+    let read = |p: &Path, f: &str| std::fs::read_to_string(p.join(f)).unwrap_or_default();
+    dir.filter_map(|e| e.ok()).any(|e| {
+        let path = e.path();
+        read(&path, "type").trim() == "bluetooth"
+            && (read(&path, "soft").trim() == "1" || read(&path, "hard").trim() == "1")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bluez_errors_classified() {
+        let err = BLEError::DbusReqErr("org.freedesktop.DBus.Error.ServiceUnknown".into());
+        assert!(matches!(
+            DaemonError::from_bluez("connect", &err),
+            DaemonError::BluetoothdNotRunning
+        ));
+        let err = BLEError::Bluez("org.bluez.Error.NotPermitted".into());
+        assert!(matches!(
+            DaemonError::from_bluez("register", &err),
+            DaemonError::PermissionDenied(_)
+        ));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let io = std::io::Error::other("bad");
+        assert_eq!(DaemonError::Config(io).exit_code(), EX_CONFIG);
+        assert_eq!(DaemonError::NoHome.exit_code(), EX_CONFIG);
+        assert_eq!(DaemonError::Hostname("x".into()).exit_code(), EX_OSERR);
+        assert_eq!(DaemonError::Rfkill.exit_code(), EX_UNAVAILABLE);
+        assert_eq!(DaemonError::NoAdapter.exit_code(), EX_UNAVAILABLE);
+        assert_eq!(
+            DaemonError::PermissionDenied("x".into()).exit_code(),
+            EX_NOPERM
+        );
+        assert_eq!(
+            DaemonError::Bluetooth("power on", "x".into()).exit_code(),
+            EX_SOFTWARE
+        );
+    }
+
+    #[test]
+    fn display() {
+        let err = DaemonError::Bluetooth("power on the adapter", "Timeout".into());
+        assert_eq!(err.to_string(), "failed to power on the adapter: Timeout");
+        assert!(DaemonError::Rfkill
+            .to_string()
+            .contains("rfkill unblock bluetooth"));
+    }
+}
@@ -0,0 +1,271 @@
+//! Sending copied files and saving received ones.
+//!
+//! Files are sent as a single clip of type `FILES_MIME`. It starts with a header with the
+//! number of files as a big endian u16, followed by the name length as a u16, the UTF-8
+//! name and the size as a u32 of each file. The contents of the files follow in order.
+
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::Clip;
+
+pub const FILES_MIME: &str = "application/x-airboard-files";
+/// The types file managers put copied files on the clipboard with.
+pub const URI_LIST_MIMES: &[&str] = &["text/uri-list", "x-special/gnome-copied-files"];
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Returns the URIs in a `text/uri-list` or `x-special/gnome-copied-files` list.
+pub fn parse_uri_list(list: &str) -> Vec<&str> {
+    list.lines()
+        .map(|l| l.trim())
+        // comments, and the action on the first line of the GNOME format
+        .filter(|l| !l.is_empty() && !l.starts_with('#') && *l != "copy" && *l != "cut")
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            ret.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(ret)
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut ret = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+/// Returns the local path of a `file://` URI.
+pub fn file_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // the host is empty or localhost for local files
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return None;
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&percent_decode(path)?)))
+}
+
+/// Returns the `text/uri-list` of `paths`.
+pub fn uri_list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| format!("file://{}\r\n", percent_encode(p.as_os_str().as_bytes())))
+        .collect()
+}
+
+/// Reads the regular files in `paths` into a clip.
+/// Fails if any isn't a regular file or they are larger than `max_bytes` together.
+pub fn pack(paths: &[PathBuf], max_bytes: usize) -> std::io::Result<Clip> {
+    if paths.is_empty() || paths.len() > u16::MAX as usize {
+        return Err(invalid("no files or too many files"));
+    }
+    let mut header = (paths.len() as u16).to_be_bytes().to_vec();
+    let mut contents = Vec::new();
+    for path in paths {
+        let meta = std::fs::metadata(path)?;
+        if !meta.is_file() {
+            return Err(invalid("only regular files can be sent"));
+        }
+        if contents.len() as u64 + meta.len() > max_bytes as u64 {
+            return Err(invalid("files are too large to send"));
+        }
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| invalid("file name is not valid UTF-8"))?;
+        let data = std::fs::read(path)?;
+        header.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        contents.extend_from_slice(&data);
+    }
+    header.extend_from_slice(&contents);
+    Ok(Clip::new(header, FILES_MIME.to_owned()))
+}
+
+/// Splits a clip created by `pack` into the names and contents of the files.
+pub fn unpack(data: &[u8]) -> std::io::Result<Vec<(String, &[u8])>> {
+    let truncated = || invalid("truncated file header");
+    let u16_at = |i: usize| -> std::io::Result<u16> {
+        let b = data.get(i..i + 2).ok_or_else(truncated)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    };
+    let count = u16_at(0)? as usize;
+    let mut pos = 2;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name_len = u16_at(pos)? as usize;
+        pos += 2;
+        let name = data.get(pos..pos + name_len).ok_or_else(truncated)?;
+        let name = std::str::from_utf8(name).map_err(|_| invalid("file name is not UTF-8"))?;
+        pos += name_len;
+        let size = data.get(pos..pos + 4).ok_or_else(truncated)?;
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        pos += 4;
+        entries.push((name.to_owned(), size));
+    }
+    let mut ret = Vec::with_capacity(count);
+    for (name, size) in entries {
+        let contents = data
+            .get(pos..pos + size)
+            .ok_or_else(|| invalid("truncated file contents"))?;
+        pos += size;
+        ret.push((name, contents));
+    }
+    Ok(ret)
+}
+
+/// Returns a name that can't escape the download directory.
+fn safe_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let base = base.trim_start_matches('.').replace('\0', "");
+    if base.is_empty() {
+        "received".to_owned()
+    } else {
+        base
+    }
+}
+
+/// Saves the files of a received clip to `dir`, never overwriting existing files.
+/// Returns the paths they were saved at.
+pub fn save(data: &[u8], dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let files = unpack(data)?;
+    std::fs::create_dir_all(dir)?;
+    let mut ret = Vec::with_capacity(files.len());
+    for (name, contents) in files {
+        let name = safe_name(&name);
+        let (stem, ext) = match name.rfind('.') {
+            Some(i) if i > 0 => (&name[..i], &name[i..]),
+            _ => (&name[..], ""),
+        };
+        let mut n = 0;
+        let (path, mut file) = loop {
+            let path = match n {
+                0 => dir.join(&name),
+                n => dir.join(format!("{} ({}){}", stem, n, ext)),
+            };
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(f) => break (path, f),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && n < 1000 => n += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        file.write_all(contents)?;
+        ret.push(path);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("airboard-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn uri_lists() {
+        let gnome = "copy\nfile:///home/me/a%20b.txt\nfile://localhost/tmp/c\n";
+        assert_eq!(
+            parse_uri_list(gnome),
+            ["file:///home/me/a%20b.txt", "file://localhost/tmp/c"]
+        );
+        let list = "# comment\r\nfile:///x\r\n\r\n";
+        assert_eq!(parse_uri_list(list), ["file:///x"]);
+
+        assert_eq!(
+            file_path("file:///home/me/a%20b.txt"),
+            Some(PathBuf::from("/home/me/a b.txt"))
+        );
+        assert_eq!(
+            file_path("file://localhost/tmp/c"),
+            Some(PathBuf::from("/tmp/c"))
+        );
+        assert_eq!(file_path("file://host/tmp/c"), None);
+        assert_eq!(file_path("https://example.com/c"), None);
+        assert_eq!(file_path("file:///bad%2"), None);
+
+        let paths = [PathBuf::from("/tmp/a b#1.txt"), PathBuf::from("/tmp/ü")];
+        let list = uri_list(&paths);
+        assert_eq!(list, "file:///tmp/a%20b%231.txt\r\nfile:///tmp/%C3%BC\r\n");
+        let parsed: Vec<_> = parse_uri_list(&list)
+            .into_iter()
+            .map(|u| file_path(u).unwrap())
+            .collect();
+        assert_eq!(parsed, paths);
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let dir = temp_dir("pack");
+        std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+        std::fs::write(dir.join("b.bin"), [0u8, 1, 2]).unwrap();
+        let paths = [dir.join("a.txt"), dir.join("b.bin")];
+        let clip = pack(&paths, 1024).unwrap();
+        assert_eq!(clip.mime(), FILES_MIME);
+        let files = unpack(clip.data()).unwrap();
+        assert_eq!(files[0], ("a.txt".to_owned(), &b"hello"[..]));
+        assert_eq!(files[1], ("b.bin".to_owned(), &[0u8, 1, 2][..]));
+        assert!(unpack(&clip.data()[..clip.len() - 1]).is_err());
+        assert!(unpack(&clip.data()[..5]).is_err());
+
+        assert!(pack(&paths, 7).is_err());
+        assert!(pack(std::slice::from_ref(&dir), 1024).is_err());
+        assert!(pack(&[], 1024).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn names_sanitized() {
+        assert_eq!(safe_name("../../.bashrc"), "bashrc");
+        assert_eq!(safe_name("C:\\Users\\me\\a.txt"), "a.txt");
+        assert_eq!(safe_name("dir/"), "received");
+        assert_eq!(safe_name(".."), "received");
+    }
+
+    #[test]
+    fn saved_without_overwriting() {
+        let src = temp_dir("save-src");
+        let dir = temp_dir("save-dst");
+        std::fs::write(src.join("a.txt"), b"new").unwrap();
+        std::fs::write(src.join("noext"), b"x").unwrap();
+        std::fs::write(dir.join("a.txt"), b"old").unwrap();
+        let clip = pack(&[src.join("a.txt"), src.join("noext")], 1024).unwrap();
+        let saved = save(clip.data(), &dir).unwrap();
+        assert_eq!(saved, [dir.join("a (1).txt"), dir.join("noext")]);
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"old");
+        assert_eq!(std::fs::read(dir.join("a (1).txt")).unwrap(), b"new");
+        let saved = save(clip.data(), &dir).unwrap();
+        assert_eq!(saved, [dir.join("a (2).txt"), dir.join("noext (1)")]);
+        std::fs::remove_dir_all(&src).ok();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,437 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Parameters of the outbound flow control.
+///
+/// The window (number of notifications in flight) is tuned with AIMD while the
+/// chunk size is probed upwards through `chunk_sizes` one step at a time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlowConfig {
+    /// Number of chunks in flight when a transfer starts.
+    pub initial_window: usize,
+    pub min_window: usize,
+    pub max_window: usize,
+    /// Window size at which growth switches from one chunk per ACK to one chunk per window.
+    pub slow_start_threshold: usize,
+    /// Factor the window is multiplied by when a chunk is lost.
+    pub decrease_factor: f64,
+    /// Number of duplicate ACKs after which the chunks in flight are considered lost.
+    pub dup_ack_threshold: u32,
+    /// Candidate lengths of the notifications, including the 4 byte offset.
+    pub chunk_sizes: Vec<usize>,
+    /// Index into `chunk_sizes` used when a transfer starts.
+    pub initial_chunk: usize,
+    /// Number of loss free windows before trying the next larger chunk size.
+    pub probe_after: u32,
+    /// A probed chunk size is kept only if the throughput is at least this
+    /// multiple of the throughput measured with the previous size.
+    pub probe_min_gain: f64,
+    /// Weight of new samples in the smoothed round trip time.
+    pub rtt_alpha: f64,
+    /// Bounds of the retransmission timeout in milliseconds, before backoff.
+    pub min_rto_ms: u64,
+    pub max_rto_ms: u64,
+    /// Number of consecutive timeouts after which a transfer is abandoned.
+    pub max_retries: u32,
+    /// Interval in milliseconds at which the header is sent until the client starts the transfer.
+    pub header_interval_ms: u64,
+}
+
+impl Default for FlowConfig {
+    fn default() -> Self {
+        Self {
+            initial_window: 2,
+            min_window: 1,
+            max_window: 16,
+            slow_start_threshold: 8,
+            decrease_factor: 0.5,
+            dup_ack_threshold: 3,
+            chunk_sizes: vec![64, 123, 169, 244, 495],
+            initial_chunk: 0,
+            probe_after: 2,
+            probe_min_gain: 1.0,
+            rtt_alpha: 0.125,
+            min_rto_ms: 250,
+            max_rto_ms: 5000,
+            max_retries: 8,
+            header_interval_ms: 200,
+        }
+    }
+}
+
+struct Probe {
+    prev_idx: usize,
+    prev_rate: f64,
+}
+
+/// Window and chunk size state of a single outbound transfer.
+pub struct FlowControl {
+    config: FlowConfig,
+    cwnd: f64,
+    ssthresh: f64,
+    size_idx: usize,
+    max_size_idx: usize,
+    clean_windows: u32,
+    window_start: Option<(Instant, u32)>,
+    acked_in_window: usize,
+    in_flight: VecDeque<(u32, Instant)>,
+    probe: Option<Probe>,
+    srtt: Option<Duration>,
+    rate: f64,
+    mtu: Option<usize>,
+}
+
+impl FlowControl {
+    pub fn new(mut config: FlowConfig) -> Self {
+        // chunks must be able to carry at least one byte after the offset
+        config.chunk_sizes.retain(|s| *s > 4);
+        if config.chunk_sizes.is_empty() {
+            config.chunk_sizes = FlowConfig::default().chunk_sizes;
+        }
+        config.chunk_sizes.sort_unstable();
+        config.chunk_sizes.dedup();
+        config.min_window = config.min_window.max(1);
+        config.max_window = config.max_window.max(config.min_window);
+        let size_idx = config.initial_chunk.min(config.chunk_sizes.len() - 1);
+        let cwnd = config
+            .initial_window
+            .max(config.min_window)
+            .min(config.max_window) as f64;
+        Self {
+            cwnd,
+            ssthresh: config.slow_start_threshold as f64,
+            size_idx,
+            max_size_idx: config.chunk_sizes.len() - 1,
+            clean_windows: 0,
+            window_start: None,
+            acked_in_window: 0,
+            in_flight: VecDeque::new(),
+            probe: None,
+            srtt: None,
+            rate: 0.0,
+            mtu: None,
+            config,
+        }
+    }
+    /// Length of the next notifications, including the 4 byte offset.
+    pub fn chunk_len(&self) -> usize {
+        self.config.chunk_sizes[self.size_idx]
+    }
+    /// Number of chunks that may be in flight.
+    pub fn window(&self) -> usize {
+        (self.cwnd as usize)
+            .max(self.config.min_window)
+            .min(self.config.max_window)
+    }
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+    /// Estimated throughput in bytes per second of the last completed window.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+    pub fn header_interval(&self) -> Duration {
+        Duration::from_millis(self.config.header_interval_ms)
+    }
+    pub fn max_retries(&self) -> u32 {
+        self.config.max_retries
+    }
+    pub fn dup_ack_threshold(&self) -> u32 {
+        self.config.dup_ack_threshold.max(1)
+    }
+    /// Retransmission timeout after `retries` consecutive timeouts.
+    ///
+    /// The base timeout is four times the smoothed round trip time and doubles with each retry.
+    pub fn rto(&self, retries: u32) -> Duration {
+        let min = Duration::from_millis(self.config.min_rto_ms);
+        let max = Duration::from_millis(self.config.max_rto_ms.max(self.config.min_rto_ms));
+        let base = match self.srtt {
+            Some(srtt) => (srtt * 4).max(min).min(max),
+            None => max,
+        };
+        base * 2u32.saturating_pow(retries.min(16))
+    }
+    pub fn on_send(&mut self, end: u32, now: Instant) {
+        self.in_flight.push_back((end, now));
+    }
+    /// Handles an ACK that advanced the client's position to `pos`.
+    pub fn on_ack(&mut self, pos: u32, now: Instant) {
+        let mut acked = 0;
+        let mut sample = None;
+        while let Some(&(end, sent)) = self.in_flight.front() {
+            if end > pos {
+                break;
+            }
+            sample = Some(now.saturating_duration_since(sent));
+            self.in_flight.pop_front();
+            acked += 1;
+        }
+        if let Some(sample) = sample {
+            self.srtt = Some(match self.srtt {
+                Some(srtt) => {
+                    let alpha = self.config.rtt_alpha;
+                    srtt.mul_f64(1.0 - alpha) + sample.mul_f64(alpha)
+                }
+                None => sample,
+            });
+        }
+        for _ in 0..acked {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1.0;
+            } else {
+                self.cwnd += 1.0 / self.cwnd;
+            }
+        }
+        self.cwnd = self.cwnd.min(self.config.max_window as f64);
+
+        let (start, start_pos) = match self.window_start {
+            Some(ws) => ws,
+            None => {
+                self.window_start = Some((now, pos));
+                self.acked_in_window = 0;
+                return;
+            }
+        };
+        self.acked_in_window += acked;
+        if self.acked_in_window < self.window() {
+            return;
+        }
+        // a full window has been acknowledged without loss
+        let secs = now.saturating_duration_since(start).as_secs_f64();
+        if secs > 0.0 {
+            self.rate = pos.saturating_sub(start_pos) as f64 / secs;
+        }
+        self.window_start = Some((now, pos));
+        self.acked_in_window = 0;
+        self.clean_windows += 1;
+        if let Some(probe) = self.probe.take() {
+            if self.rate < probe.prev_rate * self.config.probe_min_gain {
+                // the larger chunks did not help, so stop probing past the old size
+                self.size_idx = probe.prev_idx;
+                self.max_size_idx = probe.prev_idx;
+            }
+            self.clean_windows = 0;
+        } else if self.clean_windows >= self.config.probe_after && self.size_idx < self.max_size_idx
+        {
+            self.probe = Some(Probe {
+                prev_idx: self.size_idx,
+                prev_rate: self.rate,
+            });
+            self.size_idx += 1;
+            self.clean_windows = 0;
+        }
+    }
+    /// Handles the loss of the chunks after the client's position.
+    pub fn on_loss(&mut self) {
+        self.ssthresh =
+            (self.cwnd * self.config.decrease_factor).max(self.config.min_window as f64);
+        self.cwnd = self.ssthresh;
+        self.in_flight.clear();
+        self.window_start = None;
+        self.acked_in_window = 0;
+        self.clean_windows = 0;
+        if let Some(probe) = self.probe.take() {
+            self.size_idx = probe.prev_idx;
+            self.max_size_idx = probe.prev_idx;
+        }
+    }
+    /// Handles a retransmission timeout, restarting from the minimum window.
+    pub fn on_timeout(&mut self) {
+        self.on_loss();
+        self.cwnd = self.config.min_window as f64;
+    }
+    /// The negotiated ATT MTU, if it is known.
+    pub fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+    /// Sizes all chunks from the negotiated ATT MTU instead of probing for a size.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        // notifications carry a 3 byte ATT header
+        let len = mtu.saturating_sub(3).max(5);
+        self.config.chunk_sizes = vec![len];
+        self.size_idx = 0;
+        self.max_size_idx = 0;
+        self.probe = None;
+        self.mtu = Some(mtu);
+    }
+    /// Steps down to the next smaller chunk size, used when the client falls back to reads.
+    pub fn reduce_chunk(&mut self) {
+        self.probe = None;
+        self.size_idx = self.size_idx.saturating_sub(1);
+        self.clean_windows = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends and acknowledges `chunks` chunks one at a time, each taking `chunk_time`.
+    fn send_and_ack(
+        fc: &mut FlowControl,
+        pos: &mut u32,
+        now: &mut Instant,
+        chunks: usize,
+        chunk_time: Duration,
+    ) {
+        for _ in 0..chunks {
+            *pos += (fc.chunk_len() - 4) as u32;
+            fc.on_send(*pos, *now);
+            *now += chunk_time;
+            fc.on_ack(*pos, *now);
+        }
+    }
+
+    fn single_window(chunk_sizes: Vec<usize>) -> FlowConfig {
+        FlowConfig {
+            initial_window: 1,
+            max_window: 1,
+            chunk_sizes,
+            probe_after: 1,
+            ..FlowConfig::default()
+        }
+    }
+
+    #[test]
+    fn new_sanitizes_config() {
+        let fc = FlowControl::new(FlowConfig {
+            chunk_sizes: vec![4, 300, 64, 64],
+            initial_chunk: 9,
+            min_window: 0,
+            max_window: 0,
+            initial_window: 5,
+            ..FlowConfig::default()
+        });
+        assert_eq!(fc.config.chunk_sizes, [64, 300]);
+        assert_eq!(fc.chunk_len(), 300);
+        assert_eq!(fc.window(), 1);
+
+        let fc = FlowControl::new(FlowConfig {
+            chunk_sizes: vec![1, 2],
+            ..FlowConfig::default()
+        });
+        assert_eq!(fc.config.chunk_sizes, FlowConfig::default().chunk_sizes);
+    }
+
+    #[test]
+    fn aimd() {
+        let mut fc = FlowControl::new(FlowConfig {
+            chunk_sizes: vec![64],
+            ..FlowConfig::default()
+        });
+        let (mut pos, mut now) = (0, Instant::now());
+        let ms = Duration::from_millis(10);
+        assert_eq!(fc.window(), 2);
+        // slow start grows the window by one chunk per ACK up to the threshold
+        send_and_ack(&mut fc, &mut pos, &mut now, 6, ms);
+        assert_eq!(fc.window(), 8);
+        // after which it grows by about one chunk per window
+        send_and_ack(&mut fc, &mut pos, &mut now, 8, ms);
+        assert_eq!(fc.window(), 8);
+        send_and_ack(&mut fc, &mut pos, &mut now, 2, ms);
+        assert_eq!(fc.window(), 9);
+
+        fc.on_loss();
+        assert_eq!(fc.window(), 4);
+        send_and_ack(&mut fc, &mut pos, &mut now, 4, ms);
+        assert_eq!(fc.window(), 5);
+        fc.on_timeout();
+        assert_eq!(fc.window(), 1);
+    }
+
+    #[test]
+    fn window_bounds() {
+        let mut fc = FlowControl::new(FlowConfig {
+            min_window: 2,
+            max_window: 4,
+            chunk_sizes: vec![64],
+            ..FlowConfig::default()
+        });
+        let (mut pos, mut now) = (0, Instant::now());
+        send_and_ack(&mut fc, &mut pos, &mut now, 20, Duration::from_millis(10));
+        assert_eq!(fc.window(), 4);
+        fc.on_timeout();
+        assert_eq!(fc.window(), 2);
+    }
+
+    #[test]
+    fn probe_kept_when_faster() {
+        let mut fc = FlowControl::new(single_window(vec![64, 123]));
+        let (mut pos, mut now) = (0, Instant::now());
+        let ms = Duration::from_millis(10);
+        // the first ACK only starts the window, the next completes a clean one
+        send_and_ack(&mut fc, &mut pos, &mut now, 2, ms);
+        assert_eq!(fc.chunk_len(), 123);
+        assert!((fc.rate() - 6000.0).abs() < 1.0);
+        // the larger chunks take as long, so the throughput doubles
+        send_and_ack(&mut fc, &mut pos, &mut now, 3, ms);
+        assert_eq!(fc.chunk_len(), 123);
+        assert!(fc.rate() > 11000.0);
+    }
+
+    #[test]
+    fn probe_reverted_when_slower() {
+        let mut fc = FlowControl::new(single_window(vec![64, 123, 169]));
+        let (mut pos, mut now) = (0, Instant::now());
+        send_and_ack(&mut fc, &mut pos, &mut now, 2, Duration::from_millis(10));
+        assert_eq!(fc.chunk_len(), 123);
+        send_and_ack(&mut fc, &mut pos, &mut now, 1, Duration::from_millis(40));
+        assert_eq!(fc.chunk_len(), 64);
+        // the failed size is not probed again
+        send_and_ack(&mut fc, &mut pos, &mut now, 10, Duration::from_millis(10));
+        assert_eq!(fc.chunk_len(), 64);
+    }
+
+    #[test]
+    fn loss_during_probe_reverts() {
+        let mut fc = FlowControl::new(single_window(vec![64, 123]));
+        let (mut pos, mut now) = (0, Instant::now());
+        send_and_ack(&mut fc, &mut pos, &mut now, 2, Duration::from_millis(10));
+        assert_eq!(fc.chunk_len(), 123);
+        fc.on_loss();
+        assert_eq!(fc.chunk_len(), 64);
+        send_and_ack(&mut fc, &mut pos, &mut now, 10, Duration::from_millis(10));
+        assert_eq!(fc.chunk_len(), 64);
+    }
+
+    #[test]
+    fn rto() {
+        let mut fc = FlowControl::new(FlowConfig::default());
+        // without a round trip sample the maximum is used
+        assert_eq!(fc.rto(0), Duration::from_millis(5000));
+        let (mut pos, mut now) = (0, Instant::now());
+        send_and_ack(&mut fc, &mut pos, &mut now, 1, Duration::from_millis(100));
+        assert_eq!(fc.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(fc.rto(0), Duration::from_millis(400));
+        assert_eq!(fc.rto(2), Duration::from_millis(1600));
+        send_and_ack(&mut fc, &mut pos, &mut now, 50, Duration::from_millis(1));
+        assert_eq!(fc.rto(0), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn mtu_fixes_chunk_size() {
+        let mut fc = FlowControl::new(single_window(vec![64, 123]));
+        fc.set_mtu(247);
+        assert_eq!(fc.mtu(), Some(247));
+        assert_eq!(fc.chunk_len(), 244);
+        let (mut pos, mut now) = (0, Instant::now());
+        send_and_ack(&mut fc, &mut pos, &mut now, 10, Duration::from_millis(10));
+        assert_eq!(fc.chunk_len(), 244);
+    }
+
+    #[test]
+    fn reduce_chunk() {
+        let mut fc = FlowControl::new(FlowConfig {
+            initial_chunk: 2,
+            ..FlowConfig::default()
+        });
+        assert_eq!(fc.chunk_len(), 169);
+        fc.reduce_chunk();
+        assert_eq!(fc.chunk_len(), 123);
+        fc.reduce_chunk();
+        fc.reduce_chunk();
+        assert_eq!(fc.chunk_len(), 64);
+    }
+}
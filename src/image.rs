@@ -0,0 +1,232 @@
+//! Transcoding of image clips through ImageMagick.
+//!
+//! Only raster formats are handed to ImageMagick, always with the input coder given
+//! explicitly so it never guesses the format from the data.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{ImageConfig, ImageFormat};
+use crate::Clip;
+
+/// Image types that are decoded with ImageMagick, with the name of their coder.
+pub const RASTER_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpeg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/bmp", "bmp"),
+    ("image/x-bmp", "bmp"),
+    ("image/x-ms-bmp", "bmp"),
+    ("image/tiff", "tiff"),
+];
+
+/// ImageMagick is killed if it takes longer than this.
+const MAGICK_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn is_image(mime: &str) -> bool {
+    mime.starts_with("image/")
+}
+
+/// Returns the ImageMagick coder of a raster image type, `None` for any other type.
+pub fn coder(mime: &str) -> Option<&'static str> {
+    let mime = mime.split(';').next().unwrap_or("").trim();
+    RASTER_TYPES
+        .iter()
+        .find(|(m, _)| m.eq_ignore_ascii_case(mime))
+        .map(|(_, c)| *c)
+}
+
+fn unsupported(mime: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported image type {:?}", mime),
+    )
+}
+
+/// Runs an ImageMagick tool with `data` as the input image and returns its output.
+/// The tool is killed if it doesn't finish within `MAGICK_TIMEOUT`.
+pub(crate) fn magick(tool: &str, args: &[&str], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // the pipes are served by threads, so a stuck tool can't block past the timeout
+    let mut stdin = child.stdin.take().unwrap();
+    let input = data.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).map(|_| out)
+    });
+    let deadline = Instant::now() + MAGICK_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // the threads finish once the pipes are closed
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} took longer than {:?}", tool, MAGICK_TIMEOUT),
+            ));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // the tool may exit before reading all of its input, the status tells if that is a problem
+    writer.join().ok();
+    let out = reader
+        .join()
+        .unwrap_or_else(|_| Err(Error::from(ErrorKind::BrokenPipe)))?;
+    if !status.success() || out.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} failed: {}", tool, status),
+        ));
+    }
+    Ok(out)
+}
+
+/// Returns the width and height of an image decoded with `coder`.
+fn dimensions(data: &[u8], coder: &str) -> std::io::Result<(u32, u32)> {
+    let input = format!("{}:-", coder);
+    let out = magick("identify", &["-format", "%w %h\n", &input], data)?;
+    let out = String::from_utf8_lossy(&out);
+    // animated images print a line per frame
+    let mut words = out.lines().next().unwrap_or("").split_whitespace();
+    match (
+        words.next().and_then(|w| w.parse().ok()),
+        words.next().and_then(|w| w.parse().ok()),
+    ) {
+        (Some(w), Some(h)) => Ok((w, h)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "unexpected identify output",
+        )),
+    }
+}
+
+fn convert(data: &[u8], coder: &str, format: ImageFormat, extra: &[&str]) -> std::io::Result<Clip> {
+    // only the first frame of animations is kept
+    let in_arg = format!("{}:-[0]", coder);
+    let out_arg = format!("{}:-", format.magick());
+    let mut args = vec![in_arg.as_str()];
+    args.extend_from_slice(extra);
+    args.push(&out_arg);
+    let out = magick("convert", &args, data)?;
+    Ok(Clip::new(out, format.mime().to_owned()))
+}
+
+/// Re-encodes and downscales an outbound image that is too large to send quickly.
+/// Returns `None` if `clip` is sent as is.
+pub fn shrink(clip: &Clip, config: &ImageConfig) -> std::io::Result<Option<Clip>> {
+    let coder = match coder(clip.mime()) {
+        Some(c) if config.transcode => c,
+        _ => return Ok(None),
+    };
+    let too_wide = if config.max_dimension > 0 {
+        let (w, h) = dimensions(clip.data(), coder)?;
+        w > config.max_dimension || h > config.max_dimension
+    } else {
+        false
+    };
+    if !too_wide && clip.len() <= config.max_bytes {
+        return Ok(None);
+    }
+    let mut args = Vec::new();
+    let resize = format!("{0}x{0}>", config.max_dimension);
+    if config.max_dimension > 0 {
+        args.push("-resize");
+        args.push(&resize);
+    }
+    let quality = config.quality.clamp(1, 100).to_string();
+    args.push("-quality");
+    args.push(&quality);
+    let small = convert(clip.data(), coder, config.format, &args)?;
+    if small.len() >= clip.len() && !too_wide {
+        return Ok(None);
+    }
+    Ok(Some(small))
+}
+
+/// Converts a received image to PNG if the local clipboard doesn't accept its type.
+/// Returns `None` if `clip` can be written as is, and an error for images that
+/// aren't one of the `RASTER_TYPES`.
+pub fn to_local(clip: &Clip, config: &ImageConfig) -> std::io::Result<Option<Clip>> {
+    if !is_image(clip.mime()) || config.local_types.iter().any(|t| t == clip.mime()) {
+        return Ok(None);
+    }
+    let coder = coder(clip.mime()).ok_or_else(|| unsupported(clip.mime()))?;
+    convert(clip.data(), coder, ImageFormat::Png, &[]).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1x1 white GIF.
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\xff\xff\x00\x00\x00\
+        !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+    fn have_imagemagick() -> bool {
+        Command::new("convert")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    }
+
+    #[test]
+    fn local_types_kept() {
+        let config = ImageConfig::default();
+        let png = Clip::new(b"\x89PNG".to_vec(), "image/png".to_owned());
+        assert!(to_local(&png, &config).unwrap().is_none());
+        let text = Clip::new(b"hi".to_vec(), "text/plain".to_owned());
+        assert!(to_local(&text, &config).unwrap().is_none());
+        // transcoding is disabled by default
+        let gif = Clip::new(GIF.to_vec(), "image/gif".to_owned());
+        assert!(shrink(&gif, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_raster_types_converted() {
+        assert_eq!(coder("image/gif"), Some("gif"));
+        assert_eq!(coder("IMAGE/JPEG; q=1"), Some("jpeg"));
+        assert_eq!(coder("image/svg+xml"), None);
+        assert_eq!(coder("image/x-portable-anymap"), None);
+        let config = ImageConfig::default();
+        for mime in &["image/svg+xml", "image/x-unknown"] {
+            let clip = Clip::new(b"<svg/>".to_vec(), mime.to_string());
+            let err = to_local(&clip, &config).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn stuck_tool_killed() {
+        let start = Instant::now();
+        let err = magick("sleep", &["30"], b"").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn converted_to_png() {
+        if !have_imagemagick() {
+            eprintln!("ImageMagick is not installed, skipping");
+            return;
+        }
+        let gif = Clip::new(GIF.to_vec(), "image/gif".to_owned());
+        let png = to_local(&gif, &ImageConfig::default()).unwrap().unwrap();
+        assert_eq!(png.mime(), "image/png");
+        assert!(png.data().starts_with(b"\x89PNG"));
+        assert_eq!(dimensions(png.data(), "png").unwrap(), (1, 1));
+    }
+}
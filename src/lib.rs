@@ -2,11 +2,45 @@ use rustable::gatt::{AttValue, LocalChar, ValOrFn};
 use rustable::{Error as BLEError, MAX_APP_MTU};
 use sha2::{Digest, Sha256};
 
-use std::convert::TryInto;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use std::rc::Rc;
+use std::time::Instant;
+
+pub mod config;
+pub mod control;
+pub mod echo;
+pub mod error;
+pub mod files;
+pub mod flow;
+pub mod image;
+pub mod pairing;
+pub mod rich;
+pub mod sensitive;
+pub mod signals;
+pub mod stats;
+pub mod text;
+mod verify;
+
+use config::InboundConfig;
+use flow::{FlowConfig, FlowControl};
+use stats::TransferStats;
+use verify::Verifier;
+
+/// Type of the empty clip telling clients to clear their clipboard.
+pub const CLEAR_MIME: &str = "application/x-airboard-clear";
 
-const MIN_NOTIFY_LEN: usize = 64;
 const MAX_OPT_CHAR_LEN: usize = 495;
+/// Maximum number of bytes buffered ahead of the received position.
+const MAX_OOO_BYTES: usize = 64 * 1024;
+/// Maximum number of ranges reported in a selective ACK.
+const MAX_SACK_RANGES: usize = 8;
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    let mut int_buf = [0; 4];
+    int_buf.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(int_buf)
+}
 
 pub struct Clip {
     mime: String,
@@ -28,6 +62,17 @@ impl Clip {
         let hash = Sha256::digest(&data).into();
         Self { hash, data, mime }
     }
+    /// Creates the message telling clients to clear their clipboard.
+    pub fn clear() -> Self {
+        Self::new(Vec::new(), CLEAR_MIME.to_owned())
+    }
+    pub fn is_clear(&self) -> bool {
+        self.mime == CLEAR_MIME
+    }
+    /// Creates a clip whose hash has already been computed.
+    fn with_hash(data: Vec<u8>, mime: String, hash: [u8; 32]) -> Self {
+        Self { hash, data, mime }
+    }
     pub fn data(&self) -> &[u8] {
         &self.data
     }
@@ -40,6 +85,9 @@ impl Clip {
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 }
 impl Default for Clip {
     fn default() -> Self {
@@ -58,18 +106,15 @@ impl PartialEq<Clip> for Clip {
     }
 }
 
-fn optimal_mtu_downgrade(mtu: usize) -> usize {
-    if mtu >= 495 {
-        495
-    } else if mtu >= 244 {
-        244
-    } else if mtu >= 169 {
-        169
-    } else if mtu >= 123 {
-        123
-    } else {
-        mtu
-    }
+/// Progress of an outbound transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferState {
+    /// The header is being sent and the client has not started the transfer.
+    Waiting,
+    Sending,
+    Complete,
+    /// The client stopped acknowledging chunks and the retry budget is exhausted.
+    Abandoned,
 }
 
 pub struct OutSyncer {
@@ -77,83 +122,326 @@ pub struct OutSyncer {
     cur_pos: u32,
     written: u32,
     verbose: u8,
-    notify_len: usize,
-    bad_streak: bool,
+    flow: FlowControl,
+    dup_count: u32,
+    /// Duplicate ACKs are not treated as another loss until the client passes this position.
+    recover: u32,
+    high_water: u32,
+    stats: Option<TransferStats>,
+    reported: bool,
+    last_progress: Instant,
+    retries: u32,
+    abandoned: bool,
+    sacked: Vec<(u32, u32)>,
+    last_header: Option<Instant>,
 }
 
 impl OutSyncer {
     pub fn new(clip: Rc<Clip>, verbose: u8) -> Self {
+        Self::with_config(clip, verbose, FlowConfig::default())
+    }
+    pub fn with_config(clip: Rc<Clip>, verbose: u8, flow: FlowConfig) -> Self {
         OutSyncer {
             clip,
-            cur_pos: std::u32::MAX,
+            cur_pos: u32::MAX,
             written: 0,
             verbose,
-            notify_len: MIN_NOTIFY_LEN,
-            bad_streak: false,
+            flow: FlowControl::new(flow),
+            dup_count: 0,
+            recover: 0,
+            high_water: 0,
+            stats: None,
+            reported: false,
+            last_progress: Instant::now(),
+            retries: 0,
+            abandoned: false,
+            sacked: Vec::new(),
+            last_header: None,
         }
     }
     pub fn get_buf(&self) -> &[u8] {
-        &self.clip.data()
+        self.clip.data()
     }
     pub fn get_clip(&self) -> &Clip {
         &self.clip
     }
-    fn increment_notify_len(&mut self) -> usize {
-        self.notify_len += 1;
-        self.notify_len
+    pub fn get_clip_rc(&self) -> Rc<Clip> {
+        self.clip.clone()
+    }
+    pub fn state(&self) -> TransferState {
+        if self.cur_pos == u32::MAX {
+            TransferState::Waiting
+        } else if self.cur_pos as usize == self.clip.len() {
+            TransferState::Complete
+        } else if self.abandoned {
+            TransferState::Abandoned
+        } else {
+            TransferState::Sending
+        }
+    }
+    /// Number of consecutive retransmission timeouts of the current transfer.
+    pub fn retries(&self) -> u32 {
+        self.retries
     }
+    /// Current length of the notifications, including the 4 byte offset.
     pub fn notify_len(&self) -> usize {
-        self.notify_len
+        self.flow.chunk_len()
     }
-    fn reduce_notify_len(&mut self) -> Option<(usize, usize)> {
-        if self.clip.len() as u32 == self.cur_pos || self.cur_pos == std::u32::MAX {
-            let old_len = self.notify_len;
-            self.notify_len = (self.notify_len * 3 / 4).max(MIN_NOTIFY_LEN);
-            Some((old_len, self.notify_len))
-        } else {
-            None
+    /// Number of notifications that may be in flight.
+    pub fn window(&self) -> usize {
+        self.flow.window()
+    }
+    pub fn flow(&self) -> &FlowControl {
+        &self.flow
+    }
+    /// Sets the ATT MTU negotiated with the client.
+    ///
+    /// Once set, chunks are sized from the MTU rather than by probing.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        let mtu = (mtu as usize).min(MAX_APP_MTU);
+        if self.flow.mtu() != Some(mtu) {
+            if self.verbose >= 1 {
+                eprintln!("Using negotiated MTU of {}.", mtu);
+            }
+            self.flow.set_mtu(mtu);
+            self.update_stats_len();
+        }
+    }
+    fn update_stats_len(&mut self) {
+        let nl = self.flow.chunk_len();
+        if let Some(stats) = &mut self.stats {
+            stats.update_notify_len(nl);
+        }
+    }
+    fn reduce_notify_len(&mut self) {
+        if self.clip.len() as u32 == self.cur_pos || self.cur_pos == u32::MAX {
+            self.flow.reduce_chunk();
+            self.update_stats_len();
         }
     }
     pub fn indicate_local(&mut self, local_char: &mut LocalChar) -> Result<(), BLEError> {
+        self.indicate(Instant::now(), |v| {
+            local_char.notify(Some(&mut ValOrFn::Value(v)))
+        })
+    }
+    /// Sends the header or as many chunks as the window allows using `notify`.
+    ///
+    /// This is used by `indicate_local` and allows the transfer to be driven without a
+    /// real characteristic, such as by the `simulate` example.
+    pub fn indicate<F>(&mut self, now: Instant, mut notify: F) -> Result<(), BLEError>
+    where
+        F: FnMut(AttValue) -> Result<(), BLEError>,
+    {
         if self.clip.len() as u32 == self.cur_pos {
             return Ok(());
         }
-        if self.cur_pos == std::u32::MAX {
+        if self.cur_pos == u32::MAX {
+            if let Some(last) = self.last_header {
+                if now.saturating_duration_since(last) < self.flow.header_interval() {
+                    return Ok(());
+                }
+            }
+            self.last_header = Some(now);
             let v = self.generate_char(self.cur_pos, MAX_OPT_CHAR_LEN);
-            let ret = local_char.notify(Some(&mut ValOrFn::Value(v)));
-            return ret;
+            return notify(v);
         }
-        let nl = optimal_mtu_downgrade(self.notify_len);
+        if !self.check_timeout(now) {
+            return Ok(());
+        }
+        let nl = self.flow.chunk_len();
         let pload_len = nl - 4;
-        let max_out = pload_len * 6;
+        let max_out = pload_len * self.flow.window();
         let target = self.clip.len().min(self.cur_pos as usize + max_out);
 
         // we only want to send full messages
         //let mut num_msg_to_send = (target - self.written as usize) / pload_len;
-        let diff = target - self.written as usize;
-        let num_msg_to_send = if diff % pload_len == 0 || target != self.clip.len() {
-            diff / pload_len
-        } else {
-            diff / pload_len + 1
-        };
-        for _ in 0..num_msg_to_send {
+        loop {
+            self.skip_sacked();
+            let diff = target.saturating_sub(self.written as usize);
+            if diff == 0 || (diff < pload_len && target != self.clip.len()) {
+                break;
+            }
             let v = self.generate_char(self.written, nl);
             let len = v.len() - 4;
             debug_assert!(len > 0);
             if self.verbose >= 2 {
                 eprintln!("Indicating at position {}.", self.written);
             }
-            if let Err(e) = local_char.notify(Some(&mut ValOrFn::Value(v))) {
-                return Err(e);
+            notify(v)?;
+            if let Some(stats) = &mut self.stats {
+                stats.sent_bytes += len;
+                if self.written < self.high_water {
+                    stats.retransmitted_bytes += len.min((self.high_water - self.written) as usize);
+                }
             }
             self.written += len as u32;
+            self.high_water = self.high_water.max(self.written);
+            self.flow.on_send(self.written, now);
         }
         Ok(())
     }
+    /// Returns when `indicate` needs to be called again if no ACK arrives before then.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state() {
+            TransferState::Waiting => Some(match self.last_header {
+                Some(last) => last + self.flow.header_interval(),
+                None => Instant::now(),
+            }),
+            TransferState::Sending if self.written > self.cur_pos => {
+                Some(self.last_progress + self.flow.rto(self.retries))
+            }
+            _ => None,
+        }
+    }
+    /// Moves `written` past any range the client has already selectively acknowledged.
+    fn skip_sacked(&mut self) {
+        for &(start, end) in &self.sacked {
+            if start <= self.written && self.written < end {
+                self.written = end;
+            }
+        }
+    }
+    /// Returns `true` if `data` is a well formed ACK from the client.
+    ///
+    /// ACKs are the 4 byte position of the client, optionally followed by the 32 byte hash
+    /// of the clip, a count and that many pairs of 4 byte start and end offsets of chunks
+    /// received out of order.
+    pub fn is_valid_ack(data: &[u8]) -> bool {
+        match data.len() {
+            4 | 36 => true,
+            l if l > 36 => l == 37 + 8 * data[36] as usize,
+            _ => false,
+        }
+    }
+    fn parse_sack(&mut self, data: &[u8]) {
+        self.sacked.clear();
+        if data.len() <= 36 {
+            return;
+        }
+        let n = data[36] as usize;
+        for range in data[37..].chunks_exact(8).take(n) {
+            let (start, end) = (be_u32(&range[..4]), be_u32(&range[4..]));
+            if start < end && end as usize <= self.clip.len() {
+                self.sacked.push((start, end));
+            }
+        }
+        self.sacked.sort_unstable();
+    }
+    /// Rewinds to the client's position if no ACK was received within the retransmission
+    /// timeout. Returns `false` if the transfer has been abandoned.
+    fn check_timeout(&mut self, now: Instant) -> bool {
+        if self.abandoned {
+            return false;
+        }
+        if self.written <= self.cur_pos {
+            // nothing is in flight
+            self.last_progress = now;
+            return true;
+        }
+        let rto = self.flow.rto(self.retries);
+        if now.saturating_duration_since(self.last_progress) < rto {
+            return true;
+        }
+        self.retries += 1;
+        if let Some(stats) = &mut self.stats {
+            stats.timeouts += 1;
+        }
+        if self.retries > self.flow.max_retries() {
+            eprintln!(
+                "Transfer abandoned at {}/{} bytes after {} retransmission timeouts.",
+                self.cur_pos,
+                self.clip.len(),
+                self.retries - 1
+            );
+            self.abandoned = true;
+            if let Some(stats) = &mut self.stats {
+                stats.abandoned = true;
+                stats.finish(now, false);
+            }
+            return false;
+        }
+        if self.verbose >= 1 {
+            eprintln!(
+                "No ACK within {:?}, retransmitting from {} (retry {}).",
+                rto, self.cur_pos, self.retries
+            );
+        }
+        self.written = self.cur_pos;
+        self.last_progress = now;
+        self.flow.on_timeout();
+        self.update_stats_len();
+        true
+    }
+    /// Returns the statistics of the current transfer, if the client has started it.
+    pub fn stats(&self) -> Option<&TransferStats> {
+        self.stats.as_ref()
+    }
+    /// Returns the statistics of the current transfer once it has completed or been abandoned.
+    ///
+    /// The statistics are only returned once.
+    pub fn take_finished_stats(&mut self) -> Option<TransferStats> {
+        match &self.stats {
+            Some(stats) if (stats.completed || stats.abandoned) && !self.reported => {
+                self.reported = true;
+                Some(stats.clone())
+            }
+            _ => None,
+        }
+    }
+    /// Returns the statistics of an unfinished transfer, marking it as aborted.
+    /// This should be called before the `OutSyncer` is replaced.
+    pub fn take_aborted_stats(&mut self) -> Option<TransferStats> {
+        if self.reported {
+            return None;
+        }
+        let stats = self.stats.as_mut()?;
+        self.reported = true;
+        stats.finish(Instant::now(), false);
+        Some(stats.clone())
+    }
+    fn made_progress(&mut self, now: Instant) {
+        self.last_progress = now;
+        self.retries = 0;
+        if self.abandoned {
+            eprintln!("Client resumed abandoned transfer.");
+            self.abandoned = false;
+        }
+    }
+    /// Handles an ACK that repeats the client's position while chunks are in flight.
+    fn on_dup_ack(&mut self, cur_pos: u32, now: Instant) {
+        if let Some(stats) = &mut self.stats {
+            stats.dup_acks += 1;
+        }
+        // the client is still responding, even if chunks were lost
+        self.made_progress(now);
+        self.dup_count += 1;
+        // Notifications are delivered in order, so a chunk received out of order means
+        // the ones before it were lost. Otherwise only a run of duplicates counts as loss,
+        // a single one can be caused by a retransmission arriving late.
+        let lost = !self.sacked.is_empty() || self.dup_count >= self.flow.dup_ack_threshold();
+        if lost && cur_pos >= self.recover {
+            // the other duplicates of this loss are caused by the chunks still in flight,
+            // a lost retransmission is recovered by the timeout
+            self.recover = self.high_water;
+            self.written = cur_pos;
+            self.flow.on_loss();
+            self.update_stats_len();
+        }
+    }
+    fn check_finished(&mut self, now: Instant) {
+        if self.cur_pos as usize != self.clip.len() {
+            return;
+        }
+        if let Some(stats) = &mut self.stats {
+            if !stats.completed {
+                stats.finish(now, true);
+            }
+        }
+    }
     fn generate_char(&self, loc: u32, max_len: usize) -> AttValue {
         let mut v = AttValue::default();
         v.extend_from_slice(&loc.to_be_bytes());
-        if loc == std::u32::MAX {
+        if loc == u32::MAX {
             v.extend_from_slice(&self.clip.hash());
             v.extend_from_slice(&(self.clip.len() as u32).to_be_bytes());
             v.extend_from_slice(self.clip.mime().as_bytes());
@@ -172,7 +460,7 @@ impl OutSyncer {
     }
     /*
     pub fn read_loc(&self) -> AttValue {
-        if self.cur_pos == std::u32::MAX {
+        if self.cur_pos == u32::MAX {
             AttValue::from(&[255, 255, 255, 255][..])
         } else {
             AttValue::from(&self.cur_pos.to_be_bytes()[..])
@@ -191,49 +479,65 @@ impl OutSyncer {
     }
     /// If this function returns `true` it indicates there is still data to be written to the client.
     pub fn update_pos(&mut self, data: &[u8]) {
-        let mut int_buf = [0; 4];
-        int_buf.copy_from_slice(&data[..4]);
-        let cur_pos = u32::from_be_bytes(int_buf);
-        if self.cur_pos == std::u32::MAX && cur_pos as usize <= self.clip.len() {
-            if data.len() != 36 {
+        self.update_pos_at(data, Instant::now())
+    }
+    pub fn update_pos_at(&mut self, data: &[u8], now: Instant) {
+        let cur_pos = be_u32(data);
+        if self.cur_pos == u32::MAX && cur_pos as usize <= self.clip.len() {
+            if data.len() < 36 {
                 return;
             }
-            if &data[4..36] != self.clip.hash() {
+            if data[4..36] != self.clip.hash() {
                 return;
             }
             self.cur_pos = cur_pos;
             self.written = cur_pos;
+            self.last_progress = now;
+            if self.stats.is_none() {
+                self.stats = Some(TransferStats::new(
+                    self.clip.len(),
+                    self.flow.chunk_len(),
+                    now,
+                ));
+            }
+            self.check_finished(now);
             return;
         }
         if cur_pos as usize > self.clip.len() {
-            self.cur_pos = std::u32::MAX;
+            self.cur_pos = u32::MAX;
         } else {
             // self.dirty = cur_pos as usize != self.buf.len();
-            self.cur_pos = if cur_pos == std::u32::MAX
-                || (data.len() == 36 && &data[4..36] != self.clip.hash())
-            {
-                // Client in waiting for new message or bad hash received
-                std::u32::MAX
-            } else {
-                if cur_pos <= self.cur_pos {
-                    // a duplicate ACK was received
-                    self.written = cur_pos;
-                    if !self.bad_streak {
-                        // if the first of a series of notifications fails,
-                        // only reduce_notify_len on the first failure.
-                        self.reduce_notify_len();
-                        self.bad_streak = true;
-                    }
-                } else if cur_pos > self.written {
-                    // In the event of a long read cur_pos could jump self.written
-                    // so we account for that.
-                    self.written = cur_pos;
+            self.cur_pos =
+                if cur_pos == u32::MAX || (data.len() >= 36 && data[4..36] != self.clip.hash()) {
+                    // Client in waiting for new message or bad hash received
+                    u32::MAX
                 } else {
-                    self.bad_streak = false;
-                    self.increment_notify_len();
-                }
-                cur_pos
-            };
+                    self.parse_sack(data);
+                    if cur_pos < self.cur_pos {
+                        // the client discarded data, such as a block that failed verification
+                        self.written = cur_pos;
+                        self.dup_count = 0;
+                        self.made_progress(now);
+                    } else if cur_pos == self.cur_pos {
+                        if self.high_water > cur_pos {
+                            self.on_dup_ack(cur_pos, now);
+                        }
+                    } else if cur_pos > self.written {
+                        // In the event of a long read cur_pos could jump self.written
+                        // so we account for that.
+                        self.written = cur_pos;
+                        self.dup_count = 0;
+                        self.flow.on_ack(cur_pos, now);
+                        self.made_progress(now);
+                    } else {
+                        self.dup_count = 0;
+                        self.flow.on_ack(cur_pos, now);
+                        self.update_stats_len();
+                        self.made_progress(now);
+                    }
+                    cur_pos
+                };
+            self.check_finished(now);
         }
     }
 }
@@ -264,22 +568,83 @@ impl BufOrDone {
     }
 }
 
+/// Reasons an inbound transfer is rejected.
+///
+/// The code is reported to the client by replying with an offset of `u32::MAX`,
+/// the hash of the rejected clip and the code as a single byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InError {
+    HashMismatch = 1,
+    InvalidMime = 2,
+    Oversize = 3,
+    UnsupportedMime = 4,
+    /// The channel only sends clips to devices.
+    SendOnly = 5,
+    /// Syncing is paused on the server.
+    Paused = 6,
+}
+
+impl InError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InError::HashMismatch => "hash_mismatch",
+            InError::InvalidMime => "invalid_mime",
+            InError::Oversize => "oversize",
+            InError::UnsupportedMime => "unsupported_mime",
+            InError::SendOnly => "send_only",
+            InError::Paused => "paused",
+        }
+    }
+}
+
+/// Replies to a write with `error` for the clip with `hash`.
+fn error_reply(hash: &[u8], error: InError) -> AttValue {
+    let mut ret = AttValue::default();
+    ret.extend_from_slice(&u32::MAX.to_be_bytes());
+    ret.extend_from_slice(hash);
+    ret.extend_from_slice(&[error as u8]);
+    ret
+}
+
+/// Details of a rejected inbound transfer.
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    pub error: InError,
+    pub mime: String,
+    pub msg_length: u32,
+    pub received: usize,
+}
+
 pub struct InSyncer {
+    config: InboundConfig,
+    error: Option<InError>,
+    refusal: Option<InError>,
+    rejected: Option<Rejection>,
     local_clip: Rc<Clip>,
     hash: [u8; 32],
     msg_length: u32,
     data_buf: BufOrDone,
     mime: String,
+    ooo: BTreeMap<u32, Vec<u8>>,
+    ooo_bytes: usize,
+    verifier: Verifier,
 }
 
 impl Default for InSyncer {
     fn default() -> Self {
         Self {
+            config: InboundConfig::default(),
+            error: None,
+            refusal: None,
+            rejected: None,
             local_clip: Rc::new(Clip::default()),
-            msg_length: std::u32::MAX,
+            msg_length: u32::MAX,
             hash: [0; 32],
             data_buf: BufOrDone::Buf(Vec::new()),
             mime: String::new(),
+            ooo: BTreeMap::new(),
+            ooo_bytes: 0,
+            verifier: Verifier::default(),
         }
     }
 }
@@ -291,20 +656,153 @@ impl InSyncer {
             ..Default::default()
         }
     }
+    pub fn with_config(local_clip: Rc<Clip>, config: InboundConfig) -> Self {
+        Self {
+            local_clip,
+            config,
+            ..Default::default()
+        }
+    }
+    pub fn set_config(&mut self, config: InboundConfig) {
+        self.config = config;
+    }
+    /// Rejects every new transfer with `refusal` until it is cleared.
+    /// A transfer in progress is kept, so it can be resumed afterwards.
+    pub fn set_refusal(&mut self, refusal: Option<InError>) {
+        self.refusal = refusal;
+    }
+    /// Writes an unfinished transfer to `w` so it can be resumed with `load_partial`.
+    /// Returns `false` if there was nothing worth saving.
+    pub fn save_partial<W: Write>(&self, mut w: W) -> std::io::Result<bool> {
+        let buf = match &self.data_buf {
+            BufOrDone::Buf(b) if !b.is_empty() && self.error.is_none() => b,
+            _ => return Ok(false),
+        };
+        w.write_all(&self.hash)?;
+        w.write_all(&self.msg_length.to_be_bytes())?;
+        w.write_all(&(self.mime.len() as u32).to_be_bytes())?;
+        w.write_all(self.mime.as_bytes())?;
+        w.write_all(buf)?;
+        Ok(true)
+    }
+    /// Restores a transfer saved with `save_partial`, so the client can continue
+    /// from where it was when it sends the same header again.
+    pub fn load_partial<R: Read>(&mut self, mut r: R) -> std::io::Result<()> {
+        let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+        let mut hash = [0; 32];
+        r.read_exact(&mut hash)?;
+        let mut int_buf = [0; 4];
+        r.read_exact(&mut int_buf)?;
+        let msg_length = u32::from_be_bytes(int_buf);
+        r.read_exact(&mut int_buf)?;
+        let mut mime = vec![0; u32::from_be_bytes(int_buf).min(1024) as usize];
+        r.read_exact(&mut mime)?;
+        let mime = String::from_utf8(mime).map_err(|_| invalid())?;
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        if data.len() >= msg_length as usize
+            || msg_length > self.config.max_len
+            || !self.config.accepts_mime(&mime)
+        {
+            return Err(invalid());
+        }
+        self.hash = hash;
+        self.msg_length = msg_length;
+        self.mime = mime;
+        if !self.should_receive() {
+            self.msg_length = u32::MAX;
+            return Ok(());
+        }
+        self.error = None;
+        self.clear_ooo();
+        self.verifier.reset();
+        self.verifier.update(&data).ok();
+        data.reserve(msg_length as usize - data.len());
+        self.data_buf = BufOrDone::Buf(data);
+        Ok(())
+    }
+    /// Returns the details of the last rejected transfer, once.
+    pub fn take_rejection(&mut self) -> Option<Rejection> {
+        self.rejected.take()
+    }
+    fn reject(&mut self, error: InError, received: usize) {
+        self.error = Some(error);
+        self.rejected = Some(Rejection {
+            error,
+            mime: self.mime.clone(),
+            msg_length: self.msg_length,
+            received,
+        });
+    }
     fn recvd(&self) -> u32 {
         match &self.data_buf {
             BufOrDone::Buf(b) => b.len() as u32,
             BufOrDone::Done => self.msg_length,
         }
     }
+    /// Rejects the write `v` because of the refusal `error`, without touching the transfer.
+    fn refuse(&mut self, error: InError, v: &[u8]) -> AttValue {
+        if v.len() < 40 || be_u32(&v[..4]) != u32::MAX {
+            return error_reply(&self.hash, error);
+        }
+        self.rejected = Some(Rejection {
+            error,
+            mime: String::from_utf8_lossy(&v[40..]).into_owned(),
+            msg_length: be_u32(&v[36..40]),
+            received: 0,
+        });
+        error_reply(&v[4..36], error)
+    }
     fn generate_char(&self, include_hash: bool) -> AttValue {
+        if let Some(error) = self.error {
+            return error_reply(&self.hash, error);
+        }
         let mut ret = AttValue::default();
         ret.extend_from_slice(&self.recvd().to_be_bytes()[..]);
-        if include_hash {
+        if include_hash || !self.ooo.is_empty() {
             ret.extend_from_slice(&self.hash);
         }
+        if !self.ooo.is_empty() {
+            // selectively acknowledge the chunks received out of order
+            let mut ranges: Vec<(u32, u32)> = Vec::new();
+            for (start, chunk) in &self.ooo {
+                let end = start + chunk.len() as u32;
+                if let Some(last) = ranges.last_mut() {
+                    if last.1 >= *start {
+                        last.1 = last.1.max(end);
+                        continue;
+                    }
+                }
+                ranges.push((*start, end));
+            }
+            ranges.truncate(MAX_SACK_RANGES);
+            ret.extend_from_slice(&[ranges.len() as u8]);
+            for (start, end) in ranges {
+                ret.extend_from_slice(&start.to_be_bytes());
+                ret.extend_from_slice(&end.to_be_bytes());
+            }
+        }
         ret
     }
+    fn clear_ooo(&mut self) {
+        self.ooo.clear();
+        self.ooo_bytes = 0;
+    }
+    /// Buffers a chunk that arrived ahead of the received position.
+    fn buffer_ooo(&mut self, off: u32, chunk: &[u8]) {
+        if let BufOrDone::Done = self.data_buf {
+            return;
+        }
+        if off >= self.msg_length || (off - self.recvd()) as usize > MAX_OOO_BYTES {
+            return;
+        }
+        let len = chunk.len().min((self.msg_length - off) as usize);
+        if len == 0 || self.ooo_bytes + len > MAX_OOO_BYTES || self.ooo.contains_key(&off) {
+            return;
+        }
+        self.ooo.insert(off, chunk[..len].to_vec());
+        self.ooo_bytes += len;
+    }
     pub fn update_with_local(&mut self, local_clip: Rc<Clip>) {
         self.local_clip = local_clip;
     }
@@ -314,13 +812,16 @@ impl InSyncer {
             || self.mime != self.local_clip.mime
     }
     pub fn process_write(&mut self, v: &[u8]) -> (Option<Rc<Clip>>, AttValue) {
+        if let Some(error) = self.refusal {
+            return (None, self.refuse(error, v));
+        }
         if v.len() < 4 {
             return (None, self.generate_char(true));
         }
         let mut int_buf = [0; 4];
         int_buf.copy_from_slice(&v[..4]);
         let off = u32::from_be_bytes(int_buf);
-        if off == std::u32::MAX {
+        if off == u32::MAX {
             if v.len() < 40 {
                 return (None, self.generate_char(true));
             }
@@ -334,26 +835,53 @@ impl InSyncer {
                 || self.mime.as_bytes() != mime_bytes
             {
                 self.mime.clear();
+                self.error = None;
+                self.clear_ooo();
+                self.verifier.reset();
+                self.hash.copy_from_slice(hash);
                 let mime_str = match std::str::from_utf8(&v[40..]) {
                     Ok(s) => s,
                     Err(_) => {
-                        self.msg_length = std::u32::MAX;
+                        self.msg_length = u32::MAX;
+                        self.reject(InError::InvalidMime, 0);
                         return (None, self.generate_char(true));
                     }
                 };
                 self.mime.push_str(mime_str);
-                self.hash.copy_from_slice(hash);
                 self.msg_length = msg_length;
-                if self.should_receive() {
+                if msg_length > self.config.max_len {
+                    self.reject(InError::Oversize, 0);
+                    self.data_buf.reserve_and_clear(0);
+                } else if !self.config.accepts_mime(&self.mime) {
+                    self.reject(InError::UnsupportedMime, 0);
+                    self.data_buf.reserve_and_clear(0);
+                } else if self.should_receive() {
                     self.data_buf.reserve_and_clear(msg_length);
                 } else {
                     self.data_buf = BufOrDone::Done;
                 }
             }
             (None, self.generate_char(true))
+        } else if off == u32::MAX - 1 {
+            /* Block hashes: the block size, the index of the first block and the 32 byte
+              hashes of consecutive blocks.
+            */
+            if v.len() >= 12
+                && (v.len() - 12).is_multiple_of(32)
+                && self.msg_length <= self.config.max_len
+            {
+                let block_size = be_u32(&v[4..8]);
+                let first = be_u32(&v[8..12]);
+                self.verifier
+                    .add_block_hashes(self.msg_length, block_size, first, &v[12..]);
+            }
+            (None, self.generate_char(false))
+        } else if self.error.is_some() {
+            (None, self.generate_char(true))
         } else if off <= self.recvd() {
             let ret = match &mut self.data_buf {
                 BufOrDone::Buf(data_buf) => {
+                    let old_len = data_buf.len();
                     let diff = data_buf.len() - off as usize;
                     let start = diff + 4;
                     let end = v
@@ -364,18 +892,60 @@ impl InSyncer {
                             data_buf.push(*byte);
                         }
                     }
-                    if data_buf.len() == self.msg_length as usize {
-                        let clip = Clip::new(self.data_buf.take().unwrap(), self.mime.clone());
-                        if clip.hash() == self.hash {
-                            let clip = Rc::new(clip);
-                            self.local_clip = clip.clone();
-                            Some(clip)
-                        } else {
-                            let mut buf = clip.data;
-                            buf.clear();
-                            self.data_buf = BufOrDone::Buf(buf);
-                            self.msg_length = std::u32::MAX;
-                            None
+                    // append any buffered chunks that are now in order
+                    loop {
+                        let ooo_off = match self.ooo.keys().next() {
+                            Some(&o) if o as usize <= data_buf.len() => o,
+                            _ => break,
+                        };
+                        let chunk = self.ooo.remove(&ooo_off).unwrap();
+                        self.ooo_bytes -= chunk.len();
+                        let skip = data_buf.len() - ooo_off as usize;
+                        if skip < chunk.len() {
+                            data_buf.extend_from_slice(&chunk[skip..]);
+                        }
+                    }
+                    if let Err(block_start) = self.verifier.update(&data_buf[old_len..]) {
+                        eprintln!(
+                            "Block at {} of inbound clip failed verification, requesting it again.",
+                            block_start
+                        );
+                        data_buf.truncate(block_start as usize);
+                        self.ooo.clear();
+                        self.ooo_bytes = 0;
+                        None
+                    } else if data_buf.len() == self.msg_length as usize {
+                        match self.verifier.finish() {
+                            Ok(hash) if hash == self.hash => {
+                                let clip = Clip::with_hash(
+                                    self.data_buf.take().unwrap(),
+                                    self.mime.clone(),
+                                    hash,
+                                );
+                                let clip = Rc::new(clip);
+                                self.local_clip = clip.clone();
+                                Some(clip)
+                            }
+                            Err(block_start) => {
+                                eprintln!(
+                                    "Last block at {} of inbound clip failed verification, requesting it again.",
+                                    block_start
+                                );
+                                data_buf.truncate(block_start as usize);
+                                None
+                            }
+                            Ok(_) => {
+                                let mut buf = self.data_buf.take().unwrap();
+                                let received = buf.len();
+                                buf.clear();
+                                self.data_buf = BufOrDone::Buf(buf);
+                                self.clear_ooo();
+                                self.reject(InError::HashMismatch, received);
+                                // allow the client to retry by sending the header again
+                                self.msg_length = u32::MAX;
+                                self.verifier.reset();
+                                None
+                            }
                         }
                     } else {
                         None
@@ -385,7 +955,515 @@ impl InSyncer {
             };
             (ret, self.generate_char(false))
         } else {
+            self.buffer_ooo(off, &v[4..]);
             (None, self.generate_char(true))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Returns an `OutSyncer` whose client has started the transfer of `clip`.
+    fn started(clip: &Rc<Clip>, flow: FlowConfig, now: Instant) -> OutSyncer {
+        let mut out = OutSyncer::with_config(clip.clone(), 0, flow);
+        let mut start = 0u32.to_be_bytes().to_vec();
+        start.extend_from_slice(&clip.hash());
+        out.update_pos_at(&start, now);
+        out
+    }
+
+    fn header(clip: &Clip) -> Vec<u8> {
+        let mut v = u32::MAX.to_be_bytes().to_vec();
+        v.extend_from_slice(&clip.hash());
+        v.extend_from_slice(&(clip.len() as u32).to_be_bytes());
+        v.extend_from_slice(clip.mime().as_bytes());
+        v
+    }
+
+    fn chunk(clip: &Clip, off: usize, len: usize) -> Vec<u8> {
+        let mut v = (off as u32).to_be_bytes().to_vec();
+        v.extend_from_slice(&clip.data()[off..clip.len().min(off + len)]);
+        v
+    }
+
+    fn text_clip(len: usize) -> Rc<Clip> {
+        let data = (0..len).map(|i| b'a' + (i % 26) as u8).collect();
+        Rc::new(Clip::new(data, "text/plain".to_owned()))
+    }
+
+    /// Runs a transfer from `out` to `client` over a link without latency, dropping the
+    /// `n`th data notification if `drop(n)` is true. Returns the received clip.
+    fn transfer<F: FnMut(usize) -> bool>(
+        out: &mut OutSyncer,
+        client: &mut InSyncer,
+        mut drop: F,
+    ) -> Option<Rc<Clip>> {
+        let mut now = Instant::now();
+        let mut n = 0;
+        let mut received = None;
+        for _ in 0..10_000 {
+            if out.state() == TransferState::Complete {
+                return received;
+            }
+            let mut notifications = Vec::new();
+            out.indicate(now, |v| {
+                notifications.push(v);
+                Ok(())
+            })
+            .unwrap();
+            for v in notifications {
+                if be_u32(&v) != u32::MAX {
+                    n += 1;
+                    if drop(n) {
+                        continue;
+                    }
+                }
+                let (clip, ack) = client.process_write(&v);
+                received = received.or(clip);
+                out.update_pos_at(&ack, now);
+            }
+            now += Duration::from_millis(10);
+        }
+        None
+    }
+
+    #[test]
+    fn loss_free_transfer() {
+        let clip = text_clip(20_000);
+        let mut out = OutSyncer::new(clip.clone(), 0);
+        let mut client = InSyncer::default();
+        let received = transfer(&mut out, &mut client, |_| false).unwrap();
+        assert!(*received == *clip);
+        let stats = out.stats().unwrap();
+        assert!(stats.completed);
+        assert_eq!(stats.retransmitted_bytes, 0);
+        assert_eq!(stats.dup_acks, 0);
+        assert_eq!(stats.timeouts, 0);
+        // a clean link lets the chunk size be probed upwards
+        assert!(stats.max_notify_len > 64);
+    }
+
+    #[test]
+    fn lost_chunk_retransmitted_from_sack() {
+        let clip = text_clip(20_000);
+        let mut out = OutSyncer::new(clip.clone(), 0);
+        let mut client = InSyncer::default();
+        let received = transfer(&mut out, &mut client, |n| n == 5).unwrap();
+        assert!(*received == *clip);
+        let stats = out.stats().unwrap();
+        assert_eq!(stats.timeouts, 0);
+        // only the lost chunk is sent again, the ones after it were acknowledged selectively
+        assert!(stats.retransmitted_bytes > 0);
+        assert!(stats.retransmitted_bytes <= stats.max_notify_len - 4);
+    }
+
+    #[test]
+    fn chunks_sized_from_mtu() {
+        let clip = text_clip(20_000);
+        let mut out = OutSyncer::new(clip.clone(), 0);
+        out.set_mtu(100);
+        assert_eq!(out.notify_len(), 97);
+        // larger MTUs are limited to what BlueZ accepts from applications
+        out.set_mtu(517);
+        assert_eq!(out.notify_len(), MAX_APP_MTU - 3);
+        let mut client = InSyncer::default();
+        let received = transfer(&mut out, &mut client, |_| false).unwrap();
+        assert!(*received == *clip);
+        let stats = out.stats().unwrap();
+        assert_eq!(stats.min_notify_len, MAX_APP_MTU - 3);
+        assert_eq!(stats.max_notify_len, MAX_APP_MTU - 3);
+    }
+
+    #[test]
+    fn timeout_rewinds() {
+        let clip = text_clip(1000);
+        let now = Instant::now();
+        let mut out = started(&clip, FlowConfig::default(), now);
+        out.indicate(now, |_| Ok(())).unwrap();
+        assert_eq!(out.written, 120);
+        // without a round trip sample the maximum timeout is used
+        let rto = Duration::from_millis(5000);
+        assert_eq!(out.next_deadline(), Some(now + rto));
+        out.indicate(now + rto / 2, |_| Ok(())).unwrap();
+        assert_eq!(out.retries(), 0);
+
+        let mut sent = Vec::new();
+        out.indicate(now + rto, |v| {
+            sent.push(be_u32(&v));
+            Ok(())
+        })
+        .unwrap();
+        // everything in flight is resent from the client's position with the minimum window
+        assert_eq!(sent, [0]);
+        assert_eq!(out.retries(), 1);
+        assert_eq!(out.stats().unwrap().timeouts, 1);
+        // the timeout doubles with each retry
+        assert_eq!(out.next_deadline(), Some(now + rto * 3));
+    }
+
+    #[test]
+    fn abandoned_after_retries() {
+        let clip = text_clip(1000);
+        let flow = FlowConfig {
+            max_retries: 2,
+            max_rto_ms: 100,
+            ..FlowConfig::default()
+        };
+        let mut now = Instant::now();
+        let mut out = started(&clip, flow, now);
+        for _ in 0..4 {
+            out.indicate(now, |_| Ok(())).unwrap();
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(out.state(), TransferState::Abandoned);
+        assert_eq!(out.next_deadline(), None);
+        let stats = out.take_finished_stats().unwrap();
+        assert!(stats.abandoned && !stats.completed);
+        assert!(out.take_finished_stats().is_none());
+
+        // nothing is sent until the client acknowledges again
+        let mut sent = 0;
+        out.indicate(now, |_| {
+            sent += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent, 0);
+        out.update_pos_at(&60u32.to_be_bytes(), now);
+        assert_eq!(out.state(), TransferState::Sending);
+        assert_eq!(out.retries(), 0);
+    }
+
+    #[test]
+    fn duplicate_ack_threshold() {
+        let clip = text_clip(1000);
+        let now = Instant::now();
+        let mut out = started(&clip, FlowConfig::default(), now);
+        assert_eq!(out.state(), TransferState::Sending);
+        out.indicate(now, |_| Ok(())).unwrap();
+        assert_eq!(out.written, 120);
+
+        let ack = 60u32.to_be_bytes();
+        out.update_pos_at(&ack, now);
+        // a repeated ACK alone is not treated as loss
+        out.update_pos_at(&ack, now);
+        out.update_pos_at(&ack, now);
+        assert_eq!(out.written, 120);
+        out.update_pos_at(&ack, now);
+        assert_eq!(out.written, 60);
+        assert_eq!(out.stats().unwrap().dup_acks, 3);
+        // the duplicates caused by the chunks still in flight don't count as another loss
+        let window = out.window();
+        out.indicate(now, |_| Ok(())).unwrap();
+        let written = out.written;
+        for _ in 0..5 {
+            out.update_pos_at(&ack, now);
+        }
+        assert_eq!(out.written, written);
+        assert_eq!(out.window(), window);
+    }
+
+    #[test]
+    fn rewound_ack_resends() {
+        let clip = text_clip(1000);
+        let now = Instant::now();
+        let mut out = started(&clip, FlowConfig::default(), now);
+        out.indicate(now, |_| Ok(())).unwrap();
+        out.update_pos_at(&120u32.to_be_bytes(), now);
+        // the client discarded a block that failed verification
+        out.update_pos_at(&60u32.to_be_bytes(), now);
+        assert_eq!(out.cur_pos, 60);
+        assert_eq!(out.written, 60);
+    }
+
+    #[test]
+    fn out_of_order_chunks_buffered() {
+        let clip = text_clip(200);
+        let mut client = InSyncer::default();
+        client.process_write(&header(&clip));
+        let (_, ack) = client.process_write(&chunk(&clip, 120, 60));
+        let (_, ack2) = client.process_write(&chunk(&clip, 60, 60));
+        // the position stays at 0, with the hash and the received ranges merged
+        let mut expected = 0u32.to_be_bytes().to_vec();
+        expected.extend_from_slice(&clip.hash());
+        expected.push(1);
+        expected.extend_from_slice(&120u32.to_be_bytes());
+        expected.extend_from_slice(&180u32.to_be_bytes());
+        assert_eq!(&ack[..], &expected[..]);
+        assert_eq!(be_u32(&ack2), 0);
+        assert_eq!(ack2[36], 1);
+        assert_eq!(be_u32(&ack2[37..]), 60);
+        assert_eq!(be_u32(&ack2[41..]), 180);
+        assert!(OutSyncer::is_valid_ack(&ack2));
+
+        // the missing chunk completes the buffered ones
+        let (received, ack) = client.process_write(&chunk(&clip, 0, 60));
+        assert_eq!(be_u32(&ack), 180);
+        assert_eq!(ack.len(), 4);
+        assert!(received.is_none());
+        let (received, ack) = client.process_write(&chunk(&clip, 180, 60));
+        assert_eq!(be_u32(&ack), 200);
+        assert!(*received.unwrap() == *clip);
+    }
+
+    #[test]
+    fn sack_ranges_limited() {
+        let clip = text_clip(2000);
+        let mut client = InSyncer::default();
+        client.process_write(&header(&clip));
+        let mut ack = AttValue::default();
+        for i in 0..12 {
+            ack = client.process_write(&chunk(&clip, 100 + i * 100, 50)).1;
+        }
+        assert_eq!(ack[36] as usize, MAX_SACK_RANGES);
+        assert_eq!(ack.len(), 37 + 8 * MAX_SACK_RANGES);
+        assert!(OutSyncer::is_valid_ack(&ack));
+        // chunks too far ahead are not buffered
+        let mut far = chunk(&clip, 100, 50);
+        far[..4].copy_from_slice(&((MAX_OOO_BYTES + 1000) as u32).to_be_bytes());
+        client.process_write(&far);
+        assert_eq!(client.ooo.len(), 12);
+    }
+
+    #[test]
+    fn valid_acks() {
+        assert!(OutSyncer::is_valid_ack(&[0; 4]));
+        assert!(OutSyncer::is_valid_ack(&[0; 36]));
+        assert!(!OutSyncer::is_valid_ack(&[0; 3]));
+        assert!(!OutSyncer::is_valid_ack(&[0; 20]));
+        let mut sack = vec![0; 36];
+        sack.push(2);
+        sack.extend_from_slice(&[0; 16]);
+        assert!(OutSyncer::is_valid_ack(&sack));
+        sack.pop();
+        assert!(!OutSyncer::is_valid_ack(&sack));
+    }
+
+    #[test]
+    fn sacked_ranges_skipped() {
+        let clip = text_clip(1000);
+        let now = Instant::now();
+        let mut out = started(&clip, FlowConfig::default(), now);
+        out.indicate(now, |_| Ok(())).unwrap();
+        out.update_pos_at(&60u32.to_be_bytes(), now);
+        out.indicate(now, |_| Ok(())).unwrap();
+        // the chunk at 60 was lost, the client has received 120..240
+        let mut ack = 60u32.to_be_bytes().to_vec();
+        ack.extend_from_slice(&clip.hash());
+        ack.push(2);
+        for (start, end) in &[(120u32, 240u32), (500, 2000)] {
+            ack.extend_from_slice(&start.to_be_bytes());
+            ack.extend_from_slice(&end.to_be_bytes());
+        }
+        out.update_pos_at(&ack, now);
+        // ranges past the end of the clip are ignored
+        assert_eq!(out.sacked, [(120, 240)]);
+        let mut sent = Vec::new();
+        out.indicate(now, |v| {
+            sent.push(be_u32(&v));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent[0], 60);
+        assert!(!sent.contains(&120) && !sent.contains(&180));
+    }
+
+    fn error_ack(clip: &Clip, error: InError) -> Vec<u8> {
+        let mut v = u32::MAX.to_be_bytes().to_vec();
+        v.extend_from_slice(&clip.hash());
+        v.push(error as u8);
+        v
+    }
+
+    #[test]
+    fn refused_at_header() {
+        let clip = text_clip(1000);
+        let mut client = InSyncer::default();
+        client.set_refusal(Some(InError::SendOnly));
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(&ack[..], &error_ack(&clip, InError::SendOnly)[..]);
+        let rejection = client.take_rejection().unwrap();
+        assert_eq!(rejection.error, InError::SendOnly);
+        assert_eq!(rejection.mime, "text/plain");
+        assert_eq!(rejection.msg_length, 1000);
+        let (received, ack) = client.process_write(&chunk(&clip, 0, 1000));
+        assert!(received.is_none());
+        assert_eq!(ack[..4], u32::MAX.to_be_bytes());
+        assert_eq!(ack[36], InError::SendOnly as u8);
+        // only the header is reported
+        assert!(client.take_rejection().is_none());
+
+        client.set_refusal(None);
+        client.process_write(&header(&clip));
+        let (received, _) = client.process_write(&chunk(&clip, 0, 1000));
+        assert!(*received.unwrap() == *clip);
+    }
+
+    #[test]
+    fn refusal_keeps_transfer() {
+        let clip = text_clip(1000);
+        let mut client = InSyncer::default();
+        client.process_write(&header(&clip));
+        client.process_write(&chunk(&clip, 0, 400));
+        client.set_refusal(Some(InError::Paused));
+        let (received, ack) = client.process_write(&chunk(&clip, 400, 600));
+        assert!(received.is_none());
+        assert_eq!(&ack[..], &error_ack(&clip, InError::Paused)[..]);
+        client.set_refusal(None);
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(be_u32(&ack), 400);
+        let (received, _) = client.process_write(&chunk(&clip, 400, 600));
+        assert!(*received.unwrap() == *clip);
+    }
+
+    #[test]
+    fn default_inbound_accepts_any_mime() {
+        let data = (0..5000).map(|i| i as u8).collect();
+        let clip = Rc::new(Clip::new(data, "application/octet-stream".to_owned()));
+        let mut out = OutSyncer::new(clip.clone(), 0);
+        let mut client = InSyncer::default();
+        let received = transfer(&mut out, &mut client, |_| false).unwrap();
+        assert!(*received == *clip);
+        assert!(client.take_rejection().is_none());
+    }
+
+    #[test]
+    fn unsupported_mime_rejected() {
+        let data = (0..5000).map(|i| i as u8).collect();
+        let clip = Clip::new(data, "application/octet-stream".to_owned());
+        let config = InboundConfig {
+            mimes: vec!["text/*".to_owned()],
+            ..InboundConfig::default()
+        };
+        let mut client = InSyncer::with_config(Rc::new(Clip::default()), config);
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(&ack[..], &error_ack(&clip, InError::UnsupportedMime)[..]);
+        let rejection = client.take_rejection().unwrap();
+        assert_eq!(rejection.error, InError::UnsupportedMime);
+        assert_eq!(rejection.mime, "application/octet-stream");
+        assert_eq!(rejection.msg_length, 5000);
+        // the chunks are not accepted either
+        let (received, ack) = client.process_write(&chunk(&clip, 0, 5000));
+        assert!(received.is_none());
+        assert_eq!(&ack[..], &error_ack(&clip, InError::UnsupportedMime)[..]);
+
+        // text is still accepted
+        let text = text_clip(100);
+        client.process_write(&header(&text));
+        let (received, _) = client.process_write(&chunk(&text, 0, 100));
+        assert!(*received.unwrap() == *text);
+    }
+
+    #[test]
+    fn oversize_rejected() {
+        let clip = text_clip(1000);
+        let config = InboundConfig {
+            max_len: 999,
+            ..InboundConfig::default()
+        };
+        let mut client = InSyncer::with_config(Rc::new(Clip::default()), config);
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(&ack[..], &error_ack(&clip, InError::Oversize)[..]);
+        assert_eq!(client.take_rejection().unwrap().error, InError::Oversize);
+    }
+
+    #[test]
+    fn hash_mismatch_rejected() {
+        let clip = text_clip(100);
+        let mut client = InSyncer::default();
+        client.process_write(&header(&clip));
+        let mut bad = chunk(&clip, 0, 100);
+        bad[10] ^= 1;
+        let (received, ack) = client.process_write(&bad);
+        assert!(received.is_none());
+        assert_eq!(&ack[..], &error_ack(&clip, InError::HashMismatch)[..]);
+        let rejection = client.take_rejection().unwrap();
+        assert_eq!(rejection.error, InError::HashMismatch);
+        assert_eq!(rejection.received, 100);
+        // the client can retry by sending the header again
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(be_u32(&ack), 0);
+        let (received, _) = client.process_write(&chunk(&clip, 0, 100));
+        assert!(*received.unwrap() == *clip);
+    }
+
+    #[test]
+    fn corrupt_block_requested_again() {
+        let clip = text_clip(1000);
+        let mut client = InSyncer::default();
+        client.process_write(&header(&clip));
+        let mut hashes = (u32::MAX - 1).to_be_bytes().to_vec();
+        hashes.extend_from_slice(&256u32.to_be_bytes());
+        hashes.extend_from_slice(&0u32.to_be_bytes());
+        for block in clip.data().chunks(256) {
+            hashes.extend_from_slice(&Sha256::digest(block));
+        }
+        client.process_write(&hashes);
+
+        let mut bad = chunk(&clip, 0, 400);
+        bad[4 + 300] ^= 1;
+        let (_, ack) = client.process_write(&bad);
+        assert_eq!(be_u32(&ack), 400);
+        // once the block is complete, the position goes back to its start
+        let (_, ack) = client.process_write(&chunk(&clip, 400, 200));
+        assert_eq!(be_u32(&ack), 256);
+        let (received, ack) = client.process_write(&chunk(&clip, 256, 744));
+        assert_eq!(be_u32(&ack), 1000);
+        assert!(*received.unwrap() == *clip);
+    }
+
+    #[test]
+    fn header_deadlines() {
+        let clip = text_clip(100);
+        let mut out = OutSyncer::new(clip.clone(), 0);
+        let now = Instant::now();
+        let mut headers = 0;
+        let mut count = |_| {
+            headers += 1;
+            Ok(())
+        };
+        out.indicate(now, &mut count).unwrap();
+        // the header is repeated at the header interval until the client starts the transfer
+        let interval = Duration::from_millis(200);
+        assert_eq!(out.next_deadline(), Some(now + interval));
+        out.indicate(now + interval / 2, &mut count).unwrap();
+        out.indicate(now + interval, &mut count).unwrap();
+        assert_eq!(headers, 2);
+
+        let mut client = InSyncer::default();
+        transfer(&mut out, &mut client, |_| false).unwrap();
+        assert_eq!(out.state(), TransferState::Complete);
+        assert_eq!(out.next_deadline(), None);
+    }
+
+    #[test]
+    fn partial_transfer_resumed() {
+        let clip = text_clip(200);
+        let mut client = InSyncer::default();
+        let mut saved = Vec::new();
+        assert!(!client.save_partial(&mut saved).unwrap());
+        client.process_write(&header(&clip));
+        client.process_write(&chunk(&clip, 0, 120));
+        assert!(client.save_partial(&mut saved).unwrap());
+
+        let mut client = InSyncer::default();
+        client.load_partial(&saved[..]).unwrap();
+        // the client continues from the saved position after sending the header again
+        let (_, ack) = client.process_write(&header(&clip));
+        assert_eq!(be_u32(&ack), 120);
+        let (received, _) = client.process_write(&chunk(&clip, 120, 80));
+        assert!(*received.unwrap() == *clip);
+
+        // a saved transfer that is no longer accepted is discarded
+        let config = InboundConfig {
+            max_len: 100,
+            ..InboundConfig::default()
+        };
+        let mut client = InSyncer::with_config(Rc::new(Clip::default()), config);
+        assert!(client.load_partial(&saved[..]).is_err());
+        assert!(client.load_partial(&saved[..10]).is_err());
+    }
+}
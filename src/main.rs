@@ -1,51 +1,82 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use rustable::gatt::{
-    CharFlags, DescFlags, HasChildren, LocalCharBase, LocalDescBase, LocalServiceBase, ValOrFn,
+    CharFlags, DescFlags, HasChildren, LocalChar, LocalCharBase, LocalDescBase, LocalServiceBase,
+    ValOrFn,
 };
-use rustable::{AdType, Advertisement, Bluetooth, Error as BLEError, ToUUID, MAX_APP_MTU, UUID};
+use rustable::{AdType, Advertisement, Bluetooth, Error as BLEError, ToUUID, UUID};
 
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::Signal;
 use serde::{Deserialize, Serialize};
-use serde_yaml;
 
-use airboard_server::{Clip, InSyncer, OutSyncer};
-use sha2::{Digest, Sha256};
+use airboard_server::config::{
+    AdvertConfig, AdvertMode, ChannelConfig, Config, Direction, FilesConfig, InFlightPolicy,
+    PairingConfig, Selection, ServiceConfig, ServiceUuids,
+};
+use airboard_server::control::{default_socket_path, send_command, ControlServer};
+use airboard_server::echo::EchoFilter;
+use airboard_server::error::{adapter_present, rfkill_blocked, DaemonError};
+use airboard_server::pairing::{AgentEvent, PairingAgent};
+use airboard_server::sensitive::SensitiveFilter;
+use airboard_server::signals::Signals;
+use airboard_server::stats::StatsSummary;
+use airboard_server::{files, image, rich, text};
+use airboard_server::{Clip, InError, InSyncer, OutSyncer, Rejection, TransferState};
 use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env::var_os;
-use std::ffi::OsString;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdout, Command, Stdio};
 use std::rc::Rc;
-use std::thread::sleep;
 use std::time::{Duration, Instant};
 // use wl_clipboard_rs::paste::Error as PasteError;
 
-const COPY_UUID: &'static str = "4981333e-2d59-43b2-8dc3-8fedee1472c5";
-const READ_UUID: &'static str = "07178017-1879-451b-9bb5-3ff13bb85b70";
-const WRITE_UUID: &'static str = "07178017-1879-451b-9bb5-3ff13bb85b71";
-
-const VER_UUID: &'static str = "b05778f1-5a88-46a3-b6c8-2d154d629910";
-const LEN_UUID: &'static str = "b05778f1-5a88-46a3-b6c8-2d154d629911";
-const MIME_UUID: &'static str = "b05778f1-5a88-46a3-b6c8-2d154d629912";
-const HASH_UUID: &'static str = "b05778f1-5a88-46a3-b6c8-2d154d629913";
 //const LOC_UUID: &'static str = "b05778f1-5a88-46a3-b6c8-2d154d629912";
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn update_clipboard(clip: &Clip) -> Result<(), std::io::Error> {
-    let proc = Command::new("wl-copy")
+/// Adds the flag selecting the primary selection to a wl-copy or wl-paste command.
+fn select(cmd: &mut Command, selection: Selection) -> &mut Command {
+    if selection == Selection::Primary {
+        cmd.arg("--primary");
+    }
+    cmd
+}
+fn update_clipboard(clip: &Clip, selection: Selection) -> Result<(), std::io::Error> {
+    let proc = select(&mut Command::new("wl-copy"), selection)
         .arg("-t")
         .arg(clip.mime())
         .stdin(Stdio::piped())
         .spawn()?;
     proc.stdin.unwrap().write(clip.data()).map(|_| ())
 }
-fn resolve_mime_type(mut mimes: HashSet<String>) -> Option<String> {
-    if let Some(s) = mimes.take("text/plain;charset=utf-8") {
-        return Some(s);
+fn clear_clipboard(selection: Selection) -> std::io::Result<()> {
+    select(&mut Command::new("wl-copy"), selection)
+        .arg("--clear")
+        .status()
+        .map(|_| ())
+}
+/// Returns `true` if the clipboard still offers `clip`.
+fn clipboard_holds(clip: &Clip, selection: Selection) -> bool {
+    select(&mut Command::new("wl-paste"), selection)
+        .arg("-n")
+        .arg("-t")
+        .arg(clip.mime())
+        .output()
+        .is_ok_and(|out| out.status.success() && out.stdout == clip.data())
+}
+fn resolve_mime_type(mimes: HashSet<String>, rtf: bool) -> Option<String> {
+    let best_text = mimes
+        .iter()
+        .filter_map(|m| Some((text::target_rank(m)?, m)))
+        .min();
+    if let Some((_, s)) = best_text {
+        return Some(s.clone());
     }
     let mut mimes: Vec<String> = mimes.into_iter().collect();
     mimes.sort_unstable();
@@ -62,13 +93,19 @@ fn resolve_mime_type(mut mimes: HashSet<String>) -> Option<String> {
     let mut start = binary_search(&mimes[..], "text/").unwrap_err();
     while start < mimes.len() && mimes[start].starts_with("text/") {
         let second = &mimes[start][5..];
-        match second {
-            "html" => return Some(mimes.remove(start)),
-            _ => (),
+        if second == "html" {
+            return Some(mimes.remove(start));
         }
         start += 1;
     }
-    return None;
+    if rtf {
+        for rtf_mime in &["text/rtf", "application/rtf", "text/richtext"] {
+            if let Some(s) = mimes.iter().position(|m| m == rtf_mime) {
+                return Some(mimes.remove(s));
+            }
+        }
+    }
+    None
 }
 fn binary_search<T, K>(list: &[T], k: &K) -> Result<usize, usize>
 where
@@ -77,19 +114,141 @@ where
 {
     list.binary_search_by(|p| p.borrow().cmp(k))
 }
-fn get_clipboard() -> std::io::Result<Rc<Clip>> {
+/// Converts a clip read from the local clipboard to the form it is sent in.
+/// Text is sent as UTF-8, and rich text as plain text unless HTML is kept.
+fn convert_local(clip: Clip, config: &Config) -> std::io::Result<Clip> {
+    let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+    let source = String::from_utf8_lossy(clip.data());
+    let plain = match clip.mime().split(';').next().unwrap_or("").trim() {
+        "text/html" if config.rich.keep_html => {
+            let html = rich::sanitize_html(&source);
+            return Ok(Clip::new(html.into_bytes(), clip.mime().to_owned()));
+        }
+        "text/html" => rich::html_to_text(&source),
+        "text/rtf" | "application/rtf" | "text/richtext" => rich::rtf_to_text(&source),
+        _ if text::is_text(clip.mime()) => {
+            return text::to_utf8(&clip, &config.text).ok_or_else(invalid)
+        }
+        _ => return Ok(clip),
+    };
+    let plain = Clip::new(plain.into_bytes(), text::UTF8_MIME.to_owned());
+    text::to_utf8(&plain, &config.text).ok_or_else(invalid)
+}
+/// Converts a received clip to a form the local clipboard accepts.
+/// Received files are saved and replaced with a list of their URIs.
+/// Returns `None` if nothing should be written to the clipboard.
+fn convert_remote(clip: &Rc<Clip>, config: &Config) -> Option<Rc<Clip>> {
+    if clip.mime() == files::FILES_MIME {
+        if !config.files.receive {
+            return None;
+        }
+        let dir = config.files.download_dir();
+        return match files::save(clip.data(), &dir) {
+            Ok(paths) => {
+                println!("Saved {} received files to {:?}.", paths.len(), dir);
+                let list = files::uri_list(&paths).into_bytes();
+                Some(Rc::new(Clip::new(list, "text/uri-list".to_owned())))
+            }
+            Err(e) => {
+                eprintln!("Failed to save received files: {:?}", e);
+                None
+            }
+        };
+    }
+    match image::to_local(clip, &config.image) {
+        Ok(Some(c)) => return Some(Rc::new(c)),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("Not writing received image, it can't be converted: {}", e);
+            return None;
+        }
+    }
+    if clip.mime().starts_with("text/html") {
+        let html = rich::sanitize_html(&String::from_utf8_lossy(clip.data()));
+        return Some(Rc::new(Clip::new(
+            html.into_bytes(),
+            clip.mime().to_owned(),
+        )));
+    }
+    text::to_utf8(clip, &config.text)
+        .map(Rc::new)
+        .or_else(|| Some(clip.clone()))
+}
+/// Converts a received clip for the local selection, remembering the converted clip
+/// in the echo filter as that is what the selection is read back as.
+fn convert_received(
+    clip: &Rc<Clip>,
+    config: &Config,
+    echo: &mut EchoFilter,
+    origin: &str,
+) -> Option<Rc<Clip>> {
+    let local = convert_remote(clip, config)?;
+    echo.remember(origin, local.clone(), Instant::now());
+    Some(local)
+}
+/// Reads the files copied in a file manager, offered as `mime`.
+/// Returns `None` if the list contains anything but local files.
+fn get_files(
+    selection: Selection,
+    mime: &str,
+    config: &FilesConfig,
+) -> std::io::Result<Option<Clip>> {
+    let out = select(&mut Command::new("wl-paste"), selection)
+        .arg("-n")
+        .arg("-t")
+        .arg(mime)
+        .output()?;
+    let list = String::from_utf8_lossy(&out.stdout);
+    let paths: Option<Vec<PathBuf>> = files::parse_uri_list(&list)
+        .into_iter()
+        .map(files::file_path)
+        .collect();
+    let paths = match paths {
+        Some(p) if !p.is_empty() => p,
+        _ => return Ok(None),
+    };
+    match files::pack(&paths, config.max_bytes) {
+        Ok(clip) => Ok(Some(clip)),
+        Err(e) => {
+            eprintln!("Not sending copied files, sending their paths: {}", e);
+            Ok(None)
+        }
+    }
+}
+/// Reads the clipboard, failing with `PermissionDenied` if the clip must not be sent.
+fn get_clipboard(
+    selection: Selection,
+    config: &Config,
+    filter: &SensitiveFilter,
+) -> std::io::Result<Rc<Clip>> {
+    let sensitive = |reason| std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason);
     loop {
-        let mime_bytes = Command::new("wl-paste").arg("-l").output()?;
+        let mime_bytes = select(&mut Command::new("wl-paste"), selection)
+            .arg("-l")
+            .output()?;
         // let mimes = get_mime_types(ClipboardType::Regular, Seat::Unspecified)?;
         let mimes: HashSet<String> = std::str::from_utf8(&mime_bytes.stdout)
             .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?
             .lines()
             .map(|s| s.to_owned())
             .collect();
+        if let Some(reason) = filter.check_mimes(&mimes).or_else(|| filter.check_app()) {
+            return Err(sensitive(reason));
+        }
 
-        let mime = resolve_mime_type(mimes)
+        if config.files.send {
+            if let Some(uri_mime) = files::URI_LIST_MIMES.iter().find(|m| mimes.contains(**m)) {
+                if let Some(clip) = get_files(selection, uri_mime, &config.files)? {
+                    if let Some(reason) = filter.check_clip(&clip) {
+                        return Err(sensitive(reason));
+                    }
+                    return Ok(Rc::new(clip));
+                }
+            }
+        }
+        let mime = resolve_mime_type(mimes, config.rich.rtf)
             .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
-        let out = match Command::new("wl-paste")
+        let out = match select(&mut Command::new("wl-paste"), selection)
             .arg("-n")
             .arg("-t")
             .arg(&mime)
@@ -98,7 +257,11 @@ fn get_clipboard() -> std::io::Result<Rc<Clip>> {
             Ok(out) => out.stdout,
             Err(_) => continue,
         };
-        return Ok(Rc::new(Clip::new(out, mime)));
+        let clip = convert_local(Clip::new(out, mime), config)?;
+        if let Some(reason) = filter.check_clip(&clip) {
+            return Err(sensitive(reason));
+        }
+        return Ok(Rc::new(clip));
 
         /*
         let (mut out, _) = match get_contents(ClipboardType::Regular, Seat::Unspecified, MimeType::Specific(&mime))        {
@@ -130,326 +293,1629 @@ struct Handles {
     write: u16,
     write_ver: u16,
 }
-fn get_env_config_path() -> PathBuf {
-    let mut path: PathBuf = var_os("HOME").unwrap().into();
-    path.push(".config/airboard/handles");
-    path
+fn home_dir() -> std::io::Result<PathBuf> {
+    var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "HOME is not set"))
 }
-fn get_config_file<T: AsRef<Path>>(path: Option<T>) -> std::io::Result<File> {
-    match path {
-        Some(p) => File::open(p),
-        None => {
-            let path = get_env_config_path();
-            File::open(path)
-        }
+/// Returns the name of a per instance and channel file.
+fn channel_file(service: &ServiceConfig, channel: &str, base: &str) -> String {
+    let mut ret = service.instance_file(base);
+    if !channel.is_empty() {
+        ret.push('-');
+        ret.push_str(channel);
     }
+    ret
+}
+fn get_handles_path(service: &ServiceConfig, channel: &str) -> std::io::Result<PathBuf> {
+    let mut path = home_dir()?;
+    path.push(".config/airboard");
+    path.push(channel_file(service, channel, "handles"));
+    Ok(path)
 }
-fn get_write_config_file<T: AsRef<Path>>(path: Option<T>) -> std::io::Result<File> {
-    let mut oo = OpenOptions::new();
-    oo.write(true).create(true);
-    match path {
-        Some(p) => oo.open(p),
+/// Location of an unfinished inbound transfer saved on shutdown.
+fn get_partial_path(service: &ServiceConfig, channel: &str) -> std::io::Result<PathBuf> {
+    let mut path: PathBuf = match var_os("XDG_STATE_HOME") {
+        Some(dir) => dir.into(),
         None => {
-            let path = get_env_config_path();
-            std::fs::create_dir_all(path.parent().unwrap())?;
-            oo.open(path)
+            let mut home = home_dir()?;
+            home.push(".local/state");
+            home
         }
+    };
+    path.push("airboard");
+    path.push(channel_file(service, channel, "partial"));
+    Ok(path)
+}
+fn save_partial(
+    in_syncer: &InSyncer,
+    service: &ServiceConfig,
+    channel: &str,
+) -> std::io::Result<()> {
+    let path = get_partial_path(service, channel)?;
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut file = File::create(&path)?;
+    if !in_syncer.save_partial(&mut file)? {
+        drop(file);
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+fn load_partial(in_syncer: &mut InSyncer, service: &ServiceConfig, channel: &str) {
+    let path = match get_partial_path(service, channel) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+    if let Err(e) = in_syncer.load_partial(file) {
+        eprintln!("Failed to restore unfinished transfer: {:?}", e);
     }
+    std::fs::remove_file(&path).ok();
 }
-fn get_handles<T: AsRef<Path>>(path: Option<T>) -> Handles {
-    let file = match get_config_file(path) {
+fn get_handles(path: &Path) -> Handles {
+    let file = match File::open(path) {
         Ok(f) => f,
         Err(_) => return Handles::default(),
     };
     serde_yaml::from_reader(file).unwrap_or(Handles::default())
 }
-fn set_handles<T: AsRef<Path>>(path: Option<T>, handles: &Handles) -> std::io::Result<()> {
-    let file = get_write_config_file(path)?;
+fn set_handles(path: &Path, handles: &Handles) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let file = File::create(path)?;
     serde_yaml::to_writer(file, handles).map_err(|_| std::io::ErrorKind::Other.into())
 }
 
-fn main() {
-    let parser = parser();
-    let args = parser.get_matches();
-    let name = match args.value_of("hostname") {
-        Some(n) => n.to_string(),
-        None => {
-            let res = Command::new("hostname")
-                .output()
-                .expect("Failed to get device hostname!");
-            let mut n = String::from_utf8(res.stdout).expect("Invalid hostname received!");
-            n.pop();
-            n
+/// Returns the ATT MTU BlueZ passed when the client acquired the notify fd of `local_char`.
+/// This is `None` if the client is using signal based notifications.
+fn negotiated_mtu(local_char: &LocalChar) -> Option<u16> {
+    local_char.get_notify_mtu()
+}
+
+/// Returns whether a GATT client subscribed to the read characteristic of `uuids`,
+/// which clients do to receive clips. Other devices connected to the host don't count.
+fn client_subscribed(blue: &mut Bluetooth, uuids: &Uuids) -> bool {
+    let mut serv = match blue.get_service(&uuids.service) {
+        Some(s) => s,
+        None => return false,
+    };
+    let read_char = match serv.get_child(&uuids.read) {
+        Some(c) => c,
+        None => return false,
+    };
+    match read_char.get_notify_fd() {
+        // BlueZ closes the socket when the client disconnects
+        Some(fd) => {
+            let mut fds = [PollFd::new(fd, PollFlags::empty())];
+            poll(&mut fds, 0).is_ok()
+                && !fds[0]
+                    .revents()
+                    .is_some_and(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR))
         }
+        None => read_char.notifying(),
+    }
+}
+
+/// Returns the connected devices as `<address> <name>`, as reported by bluetoothctl.
+fn connected_devices() -> std::io::Result<Vec<String>> {
+    let out = Command::new("bluetoothctl")
+        .arg("devices")
+        .arg("Connected")
+        .output()?;
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|l| l.strip_prefix("Device "))
+        .map(|l| l.to_owned())
+        .collect())
+}
+fn peer_address(peer: &str) -> &str {
+    peer.split_whitespace().next().unwrap_or(peer)
+}
+/// Logs a rejected inbound transfer and warns if the connected devices repeatedly fail.
+fn report_rejection(
+    rej: &Rejection,
+    peers: &[String],
+    failures: &mut HashMap<String, u32>,
+    threshold: u32,
+) {
+    let peer_desc = if peers.is_empty() {
+        "unknown device".to_owned()
+    } else {
+        peers.join(", ")
     };
-    let verbose = args.occurrences_of("verbose") as u8;
-    set_verbose(verbose);
-    let mut handles = get_handles::<&Path>(None);
-    println!("Starting service with handles: {:?}", handles);
-
-    let mut blue;
-    let serv_uuid = COPY_UUID.to_uuid();
-    let read_uuid = READ_UUID.to_uuid();
-    let write_uuid = WRITE_UUID.to_uuid();
-    let ver_uuid = VER_UUID.to_uuid();
-    let (in_syncer, out_syncer) =  loop {
-        blue = Bluetooth::new(
-            "io.maves.airboard".to_string(),
-            "/org/bluez/hci0".to_string(),
-        )
-        .unwrap();
-        blue.verbose = verbose;
-        if args.is_present("no-filter") {
-            blue.set_filter(None).unwrap();
+    eprintln!(
+        "Rejected clip from {}: {} (mime {:?}, {} of {} bytes received)",
+        peer_desc,
+        rej.error.name(),
+        rej.mime,
+        rej.received,
+        rej.msg_length
+    );
+    for peer in peers {
+        let count = failures.entry(peer_address(peer).to_owned()).or_insert(0);
+        *count += 1;
+        if *count >= threshold {
+            eprintln!(
+                "Warning: {} consecutive failed transfers from {}.",
+                count, peer
+            );
         }
-        let mut copy_service = LocalServiceBase::new(&serv_uuid, true);
-        copy_service.set_handle(handles.service);
+    }
+}
 
-        let cur_clip = match get_clipboard() {
-            Ok(o) => o,
+/// Returns the fd of the D-Bus connection used by `blue`.
+fn dbus_fd(blue: &Bluetooth) -> RawFd {
+    blue.as_raw_fd()
+}
+/// Returns the socket acquired by a client with AcquireWrite, if any.
+fn char_write_fd(local_char: &LocalChar) -> Option<RawFd> {
+    local_char.get_write_fd()
+}
+
+/// Watches the clipboard with `wl-paste --watch`, which prints a line for every change.
+struct ClipWatcher {
+    child: Child,
+    stdout: ChildStdout,
+}
+impl ClipWatcher {
+    fn spawn(selection: Selection) -> std::io::Result<Self> {
+        let mut child = select(&mut Command::new("wl-paste"), selection)
+            .arg("--watch")
+            .arg("echo")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Self { child, stdout })
+    }
+    /// Consumes the pending change notifications. Returns `false` if the watcher has exited.
+    fn drain(&mut self) -> bool {
+        let mut buf = [0; 256];
+        match self.stdout.read(&mut buf) {
+            Ok(0) | Err(_) => false,
+            Ok(_) => true,
+        }
+    }
+}
+/// Watches the local selection of a channel, waiting for changes to settle before reading it.
+struct LocalWatch {
+    watcher: Option<ClipWatcher>,
+    /// Whether the selection is polled because it can't be watched.
+    polling: bool,
+    /// When to read the changed selection.
+    settle_at: Option<Instant>,
+}
+impl LocalWatch {
+    fn new(channel: &Channel) -> Self {
+        if !channel.direction().sends() {
+            return Self {
+                watcher: None,
+                polling: false,
+                settle_at: None,
+            };
+        }
+        match ClipWatcher::spawn(channel.selection()) {
+            Ok(w) => Self {
+                watcher: Some(w),
+                polling: false,
+                settle_at: None,
+            },
             Err(e) => {
-                eprintln!("Failed to read clipboard: {:?}", e);
-                Rc::new(Clip::default())
+                eprintln!("Failed to watch clipboard, polling instead: {:?}", e);
+                Self {
+                    watcher: None,
+                    polling: true,
+                    settle_at: None,
+                }
             }
-        };
+        }
+    }
+}
+impl AsRawFd for ClipWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stdout.as_raw_fd()
+    }
+}
+impl Drop for ClipWatcher {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
 
-        let in_syncer = Rc::new(RefCell::new(InSyncer::new(cur_clip.clone())));
-        let out_syncer = Rc::new(RefCell::new(OutSyncer::new(cur_clip, verbose)));
+fn write_stats_file(path: &Path, summary: &StatsSummary) -> std::io::Result<()> {
+    // write to a temporary file first so scrapers never see a partial file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(summary.to_prometheus().as_bytes())?;
+    drop(file);
+    std::fs::rename(&tmp, path)
+}
+/// Moves the stats of a finished or aborted transfer into the summary.
+/// Returns `true` if any stats were recorded.
+fn record_stats(summary: &RefCell<StatsSummary>, os: &mut OutSyncer, aborted: bool) -> bool {
+    let stats = if aborted {
+        os.take_aborted_stats()
+    } else {
+        os.take_finished_stats()
+    };
+    if let Some(stats) = stats {
+        if verbose() > 0 {
+            eprintln!("Transfer finished: {}", stats);
+        }
+        summary.borrow_mut().record(stats);
+        true
+    } else {
+        false
+    }
+}
+fn status_report(os: &OutSyncer) -> String {
+    let mut ret = format!("clip: {:?}\n", os.get_clip());
+    match os.flow().mtu() {
+        Some(mtu) => ret.push_str(&format!("mtu: {}\n", mtu)),
+        None => ret.push_str("mtu: unknown, probing\n"),
+    }
+    match (os.state(), os.stats()) {
+        (TransferState::Waiting, _) | (_, None) => ret.push_str("transfer: waiting for client\n"),
+        (TransferState::Complete, _) => ret.push_str("transfer: complete\n"),
+        (TransferState::Abandoned, Some(stats)) => ret.push_str(&format!(
+            "transfer: abandoned after {} timeouts, {} bytes sent\n",
+            stats.timeouts, stats.sent_bytes
+        )),
+        (TransferState::Sending, Some(stats)) => ret.push_str(&format!(
+            "transfer: in progress, {} bytes sent, notify_len {}, window {}, {} retries\n",
+            stats.sent_bytes,
+            os.notify_len(),
+            os.window(),
+            os.retries()
+        )),
+    }
+    ret
+}
+fn peer_addresses(peers: &[String]) -> Vec<&str> {
+    peers.iter().map(|p| peer_address(p)).collect()
+}
 
-        /*
-           The read and write services are from the prespective of the client. So
-           for this program we read the write_char for updates from the client (typically a phone)
-           and write to the read_char for updates to the client from this device.
-        */
-        let mut read_flags = CharFlags::default();
-        // perimissions
-        read_flags.secure_read = true;
-        read_flags.encrypt_read = true;
-        read_flags.notify = true;
-        read_flags.indicate = true;
-        read_flags.encrypt_write = true;
-        read_flags.write_wo_response = true;
-        // create read characteristic
-        let mut read_char = LocalCharBase::new(&read_uuid, read_flags);
-        // neable the write fd and setup the write callback
-        read_char.enable_write_fd(true);
-        read_char.set_handle(handles.read);
-
-        let os_clone = out_syncer.clone();
-        read_char.write_callback = Some(Box::new(move |data| {
-            if verbose >= 2 {
-                eprintln!(
-                    "read_char.write_callback(): Read characteristic written to with: {:?}",
-                    data
-                );
+/// Whether clips are synced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SyncMode {
+    Active,
+    /// Local changes aren't sent and new transfers from devices are rejected.
+    Paused,
+    /// Paused, except that the next local change is sent.
+    Once,
+}
+
+/// Returns the error new inbound transfers are rejected with, if any.
+fn refusal(mode: SyncMode, direction: Direction) -> Option<InError> {
+    if !direction.receives() {
+        Some(InError::SendOnly)
+    } else if mode != SyncMode::Active {
+        Some(InError::Paused)
+    } else {
+        None
+    }
+}
+
+/// State shared between the GATT callbacks and the event loop.
+/// It outlives the connection to bluetoothd so nothing is lost when it restarts.
+struct Shared {
+    config: Rc<RefCell<Config>>,
+    summary: Rc<RefCell<StatsSummary>>,
+    peers: Rc<RefCell<Vec<String>>>,
+    failures: Rc<RefCell<HashMap<String, u32>>>,
+    verbose: u8,
+    bus_name: String,
+    sensitive: RefCell<SensitiveFilter>,
+    mode: Rc<Cell<SyncMode>>,
+    channels: Vec<Channel>,
+}
+
+/// A clipboard synced through its own GATT service.
+struct Channel {
+    config: Rc<RefCell<ChannelConfig>>,
+    in_syncer: Rc<RefCell<InSyncer>>,
+    out_syncer: Rc<RefCell<OutSyncer>>,
+    /// Local clips waiting for the transfer in progress to finish.
+    pending: Rc<RefCell<VecDeque<Rc<Clip>>>>,
+    /// The last clip read from the local selection, before it was transcoded for sending.
+    local: RefCell<Rc<Clip>>,
+    echo: Rc<RefCell<EchoFilter>>,
+    /// The clip being served and when it started being served, to expire it.
+    serving: RefCell<Option<(Rc<Clip>, Instant)>>,
+    /// The last received clip, as written to the local clipboard.
+    last_remote: Rc<RefCell<Option<Rc<Clip>>>>,
+    uuids: Uuids,
+    handles: RefCell<Handles>,
+    handles_path: PathBuf,
+}
+impl Channel {
+    fn new(config: &Config, ch: ChannelConfig, filter: &SensitiveFilter, verbose: u8) -> Self {
+        let service = &config.service;
+        let cur_clip = if !ch.direction.sends() {
+            Rc::new(Clip::default())
+        } else {
+            match get_clipboard(ch.selection, config, filter) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("Failed to read clipboard: {:?}", e);
+                    Rc::new(Clip::default())
+                }
             }
-            if data.len() != 4 && data.len() != 36 {
-                return Err((
-                    "org.bluez.DBus.Failed".to_string(),
-                    Some("Data was not 4 or 36 bytes long".to_string()),
-                ));
+        };
+        let mut in_syncer = InSyncer::with_config(cur_clip.clone(), config.inbound_for(&ch));
+        load_partial(&mut in_syncer, service, &ch.name);
+        let out_clip = outbound_clip(config, cur_clip.clone());
+        let out_syncer = OutSyncer::with_config(out_clip, verbose, config.flow.clone());
+        let handles_path =
+            get_handles_path(service, &ch.name).unwrap_or_else(|_| fail(DaemonError::NoHome));
+        let handles = get_handles(&handles_path);
+        let uuids = service.channel_uuids(&ch);
+        println!(
+            "Starting channel {:?} on service {} ({}) with handles: {:?}",
+            ch.name,
+            service.bus_name(),
+            uuids.service,
+            handles
+        );
+        Self {
+            config: Rc::new(RefCell::new(ch)),
+            in_syncer: Rc::new(RefCell::new(in_syncer)),
+            out_syncer: Rc::new(RefCell::new(out_syncer)),
+            pending: Rc::new(RefCell::new(VecDeque::new())),
+            local: RefCell::new(cur_clip),
+            echo: Rc::new(RefCell::new(EchoFilter::new(
+                Duration::from_secs(config.echo.ttl_secs),
+                config.echo.per_origin,
+            ))),
+            serving: RefCell::new(None),
+            last_remote: Rc::new(RefCell::new(None)),
+            uuids: Uuids::new(&uuids),
+            handles: RefCell::new(handles),
+            handles_path,
+        }
+    }
+    fn name(&self) -> String {
+        RefCell::borrow(&self.config).name.clone()
+    }
+    fn selection(&self) -> Selection {
+        RefCell::borrow(&self.config).selection
+    }
+    fn direction(&self) -> Direction {
+        RefCell::borrow(&self.config).direction
+    }
+    fn active(&self, peers: &[String]) -> bool {
+        RefCell::borrow(&self.config).active_with(&peer_addresses(peers))
+    }
+    /// Replaces the outbound transfer with one of `clip`, recording the stats of the old one.
+    fn reset_out(&self, shared: &Shared, clip: Rc<Clip>) {
+        record_stats(&shared.summary, &mut self.out_syncer.borrow_mut(), true);
+        let flow = RefCell::borrow(&shared.config).flow.clone();
+        self.out_syncer
+            .replace(OutSyncer::with_config(clip, shared.verbose, flow));
+    }
+    /// Sends a new local clip, or keeps it until the transfer in progress has finished.
+    fn push_local(&self, shared: &Shared, clip: Rc<Clip>) {
+        self.local.replace(clip.clone());
+        let clip = outbound_clip(&RefCell::borrow(&shared.config), clip);
+        let local = RefCell::borrow(&shared.config).local.clone();
+        let sending = RefCell::borrow(&self.out_syncer).state() == TransferState::Sending;
+        let mut pending = self.pending.borrow_mut();
+        match local.in_flight {
+            InFlightPolicy::Finish if sending => {
+                pending.clear();
+                pending.push_back(clip);
             }
-            os_clone.borrow_mut().update_pos(data);
-            Ok((None, false))
-        }));
-
-        let os_clone = out_syncer.clone();
-        read_char.write_val_or_fn(&mut ValOrFn::Function(Box::new(move || {
-            if verbose > 2 {
-                eprintln!("Read characteristic read.");
+            InFlightPolicy::Queue if sending || !pending.is_empty() => {
+                if pending.len() >= local.max_queue.max(1) {
+                    let dropped = pending.pop_front();
+                    eprintln!("Clip queue is full, dropping {:?}", dropped.unwrap());
+                }
+                pending.push_back(clip);
+            }
+            _ => {
+                pending.clear();
+                drop(pending);
+                self.reset_out(shared, clip);
+            }
+        }
+    }
+    /// Returns when the clip being served expires.
+    fn expires_at(&self, shared: &Shared) -> Option<Instant> {
+        let ttl = RefCell::borrow(&shared.config).expiry.ttl()?;
+        match &*RefCell::borrow(&self.serving) {
+            Some((clip, since)) if !clip.is_clear() => Some(*since + ttl),
+            _ => None,
+        }
+    }
+    /// Stops serving the clip once it has expired, telling the clients to clear it.
+    fn check_expiry(&self, shared: &Shared, now: Instant) {
+        let clip = RefCell::borrow(&self.out_syncer).get_clip_rc();
+        let changed = match &*RefCell::borrow(&self.serving) {
+            Some((serving, _)) => !Rc::ptr_eq(serving, &clip),
+            None => true,
+        };
+        if changed {
+            self.serving.replace(Some((clip, now)));
+        }
+        match self.expires_at(shared) {
+            Some(at) if now >= at => (),
+            _ => return,
+        }
+        println!("Clip expired, telling clients to clear it.");
+        self.pending.borrow_mut().clear();
+        self.reset_out(shared, Rc::new(Clip::clear()));
+        let remote = self.last_remote.borrow_mut().take();
+        if !RefCell::borrow(&shared.config).expiry.clear_local {
+            return;
+        }
+        if let Some(remote) = remote {
+            if clipboard_holds(&remote, self.selection()) {
+                println!("Clearing expired remote clip from the local clipboard.");
+                if let Err(e) = clear_clipboard(self.selection()) {
+                    eprintln!("Failed to clear clipboard: {:?}", e);
+                }
             }
-            os_clone.borrow_mut().read_fn()
-        })));
+        }
+    }
+    /// Starts sending the next waiting clip once the transfer in progress has finished.
+    /// A clip whose header hasn't been read yet is not replaced, so queued clips aren't skipped.
+    fn advance(&self, shared: &Shared) {
+        match RefCell::borrow(&self.out_syncer).state() {
+            TransferState::Complete | TransferState::Abandoned => (),
+            TransferState::Waiting | TransferState::Sending => return,
+        }
+        let next = self.pending.borrow_mut().pop_front();
+        if let Some(clip) = next {
+            if shared.verbose > 0 {
+                eprintln!("Sending waiting clip: {:?}", clip);
+            }
+            self.reset_out(shared, clip);
+        }
+    }
+}
+/// Returns the clip to send for a local clip, shrinking large images.
+fn outbound_clip(config: &Config, clip: Rc<Clip>) -> Rc<Clip> {
+    match image::shrink(&clip, &config.image) {
+        Ok(Some(small)) => {
+            println!(
+                "Transcoded image from {} bytes of {} to {} bytes of {}.",
+                clip.len(),
+                clip.mime(),
+                small.len(),
+                small.mime()
+            );
+            Rc::new(small)
+        }
+        Ok(None) => clip,
+        Err(e) => {
+            eprintln!("Failed to transcode image, sending the original: {:?}", e);
+            clip
+        }
+    }
+}
+fn inactive<T>() -> Result<T, (String, Option<String>)> {
+    Err((
+        "org.bluez.Error.NotPermitted".to_string(),
+        Some("None of the devices this channel is served for is connected".to_string()),
+    ))
+}
 
-        // create protocol version descriptor
-        let mut ver_flags = DescFlags::default();
-        ver_flags.read = true;
-        ver_flags.encrypt_read = true;
-        ver_flags.secure_read = true;
-        let mut ver_desc = LocalDescBase::new(&ver_uuid, ver_flags);
-        ver_desc.vf = ValOrFn::Value([1_u8, 0][..].into());
-        ver_desc.set_handle(handles.read_ver);
+struct Uuids {
+    service: UUID,
+    read: UUID,
+    write: UUID,
+    ver: UUID,
+    len: UUID,
+    mime: UUID,
+    hash: UUID,
+}
+impl Uuids {
+    fn new(u: &ServiceUuids) -> Self {
+        Self {
+            service: u.service.as_str().to_uuid(),
+            read: u.read.as_str().to_uuid(),
+            write: u.write.as_str().to_uuid(),
+            ver: u.ver.as_str().to_uuid(),
+            len: u.len.as_str().to_uuid(),
+            mime: u.mime.as_str().to_uuid(),
+            hash: u.hash.as_str().to_uuid(),
+        }
+    }
+}
 
-        /*
-        let mut loc_desc = LocalDescBase::new(LOC_UUID, ver_flags);
-        let os_clone = out_syncer.clone();
-        loc_desc.vf = ValOrFn::Function(Box::new(move || {
-            os_clone.borrow().read_loc()
-        }));
-        */
+fn build_service(shared: &Shared, channel: &Channel) -> LocalServiceBase {
+    let verbose = shared.verbose;
+    let (config, summary, peers, failures) = (
+        &shared.config,
+        &shared.summary,
+        &shared.peers,
+        &shared.failures,
+    );
+    let (in_syncer, out_syncer) = (&channel.in_syncer, &channel.out_syncer);
+    let handles = &*RefCell::borrow(&channel.handles);
+    let uuids = &channel.uuids;
+    let (read_uuid, write_uuid, ver_uuid) = (&uuids.read, &uuids.write, &uuids.ver);
+    let mut copy_service = LocalServiceBase::new(&uuids.service, true);
+    copy_service.set_handle(handles.service);
 
-        let mut len_desc = LocalDescBase::new(LEN_UUID, ver_flags);
-        let os_clone = out_syncer.clone();
-        len_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_len()));
-
-        let mut mime_desc = LocalDescBase::new(MIME_UUID, ver_flags);
-        let os_clone = out_syncer.clone();
-        mime_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_mime()));
-
-        let mut mime_desc = LocalDescBase::new(MIME_UUID, ver_flags);
-        let os_clone = out_syncer.clone();
-        mime_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_mime()));
-
-        let mut hash_desc = LocalDescBase::new(HASH_UUID, ver_flags);
-        let os_clone = out_syncer.clone();
-        hash_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_hash()));
-
-        read_char.add_desc(ver_desc);
-        //read_char.add_desc(loc_desc);
-        read_char.add_desc(len_desc);
-        read_char.add_desc(mime_desc);
-        read_char.add_desc(hash_desc);
-
-        copy_service.add_char(read_char);
-        //permissions
-        let mut write_flags = CharFlags::default();
-        write_flags.secure_write = true;
-        write_flags.encrypt_write = true;
-        write_flags.write_wo_response = true;
-        write_flags.encrypt_read = true;
-        write_flags.notify = true;
-        write_flags.indicate = true;
-        let mut write_char = LocalCharBase::new(&write_uuid, write_flags);
-        // setup write call back
-        write_char.enable_write_fd(true);
-        write_char.set_handle(handles.write);
-        //let last_written = Rc::new(RefCell::new(Clip::default()));
-        //let lw_clone = last_written.clone();
-        // let (v, l) = syncer.read_fn();
-        let os_clone = out_syncer.clone();
-        let is_clone = in_syncer.clone();
-
-        write_char.write_callback = Some(Box::new(move |bytes| {
-            let (clip, val) = is_clone.borrow_mut().process_write(bytes);
-            if verbose >= 2 {
-                eprintln!("Received message: {:?}", bytes);
-                eprintln!("write_char.write_callback(): replying with: {:?}", val);
-            }
-            if let Some(clip) = clip {
-                update_clipboard(&clip).ok();
-                println!("Updading clipboard with new remote clip: {:?}", clip);
-                //lw_clone.replace(clip);
-                os_clone.replace(OutSyncer::new(clip, verbose));
-            }
-            Ok((Some(ValOrFn::Value(val)), true))
-        }));
-
-        let mut ver_desc = LocalDescBase::new(&ver_uuid, ver_flags);
-        ver_desc.vf = ValOrFn::Value([0, 0][..].into());
-        ver_desc.set_handle(handles.write_ver);
-        write_char.add_desc(ver_desc);
-        copy_service.add_char(write_char);
-        /*
-        let mut write_serv = copy_service.get_char(&write_uuid);
-        write_serv.write_val_or_fn(&mut ValOrFn::Value(v, l));*/
+    /*
+       The read and write services are from the prespective of the client. So
+       for this program we read the write_char for updates from the client (typically a phone)
+       and write to the read_char for updates to the client from this device.
+    */
+    // perimissions
+    let read_flags = CharFlags {
+        secure_read: true,
+        encrypt_read: true,
+        notify: true,
+        indicate: true,
+        encrypt_write: true,
+        write_wo_response: true,
+        ..Default::default()
+    };
+    // create read characteristic
+    let mut read_char = LocalCharBase::new(read_uuid, read_flags);
+    // neable the write fd and setup the write callback
+    read_char.enable_write_fd(true);
+    read_char.set_handle(handles.read);
+
+    let os_clone = out_syncer.clone();
+    let ch_clone = channel.config.clone();
+    let peers_clone = peers.clone();
+    read_char.write_callback = Some(Box::new(move |data| {
+        if !RefCell::borrow(&ch_clone).active_with(&peer_addresses(&RefCell::borrow(&peers_clone)))
+        {
+            return inactive();
+        }
+        if verbose >= 2 {
+            eprintln!(
+                "read_char.write_callback(): Read characteristic written to with: {:?}",
+                data
+            );
+        }
+        if !OutSyncer::is_valid_ack(data) {
+            return Err((
+                "org.bluez.DBus.Failed".to_string(),
+                Some("Data was not a valid ACK".to_string()),
+            ));
+        }
+        os_clone.borrow_mut().update_pos(data);
+        Ok((None, false))
+    }));
+
+    let os_clone = out_syncer.clone();
+    read_char.write_val_or_fn(&mut ValOrFn::Function(Box::new(move || {
+        if verbose > 2 {
+            eprintln!("Read characteristic read.");
+        }
+        os_clone.borrow_mut().read_fn()
+    })));
+
+    // create protocol version descriptor
+    let ver_flags = DescFlags {
+        read: true,
+        encrypt_read: true,
+        secure_read: true,
+        ..Default::default()
+    };
+    let mut ver_desc = LocalDescBase::new(ver_uuid, ver_flags);
+    // the second byte is bumped for selective ACK support
+    ver_desc.vf = ValOrFn::Value([1_u8, 1][..].into());
+    ver_desc.set_handle(handles.read_ver);
 
-        blue.add_service(copy_service).unwrap();
-        /*loop {
-            blue.process_requests().unwrap();
-        }*/
+    /*
+    let mut loc_desc = LocalDescBase::new(LOC_UUID, ver_flags);
+    let os_clone = out_syncer.clone();
+    loc_desc.vf = ValOrFn::Function(Box::new(move || {
+        os_clone.borrow().read_loc()
+    }));
+    */
+
+    let mut len_desc = LocalDescBase::new(&uuids.len, ver_flags);
+    let os_clone = out_syncer.clone();
+    len_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_len()));
+
+    let mut mime_desc = LocalDescBase::new(&uuids.mime, ver_flags);
+    let os_clone = out_syncer.clone();
+    mime_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_mime()));
+
+    let mut mime_desc = LocalDescBase::new(&uuids.mime, ver_flags);
+    let os_clone = out_syncer.clone();
+    mime_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_mime()));
+
+    let mut hash_desc = LocalDescBase::new(&uuids.hash, ver_flags);
+    let os_clone = out_syncer.clone();
+    hash_desc.vf = ValOrFn::Function(Box::new(move || RefCell::borrow(&os_clone).read_hash()));
+
+    read_char.add_desc(ver_desc);
+    //read_char.add_desc(loc_desc);
+    read_char.add_desc(len_desc);
+    read_char.add_desc(mime_desc);
+    read_char.add_desc(hash_desc);
+
+    copy_service.add_char(read_char);
+    //permissions
+    let write_flags = CharFlags {
+        secure_write: true,
+        encrypt_write: true,
+        write_wo_response: true,
+        encrypt_read: true,
+        notify: true,
+        indicate: true,
+        ..Default::default()
+    };
+    let mut write_char = LocalCharBase::new(write_uuid, write_flags);
+    // setup write call back
+    write_char.enable_write_fd(true);
+    write_char.set_handle(handles.write);
+    //let last_written = Rc::new(RefCell::new(Clip::default()));
+    //let lw_clone = last_written.clone();
+    // let (v, l) = syncer.read_fn();
+    let os_clone = out_syncer.clone();
+    let is_clone = in_syncer.clone();
+    let sum_clone = summary.clone();
+    let conf_clone = config.clone();
+    let ch_clone = channel.config.clone();
+    let pending_clone = channel.pending.clone();
+    let echo_clone = channel.echo.clone();
+    let remote_clone = channel.last_remote.clone();
+    let mode_clone = shared.mode.clone();
+    let peers_clone = peers.clone();
+    let failures_clone = failures.clone();
+
+    write_char.write_callback = Some(Box::new(move |bytes| {
+        let channel = RefCell::borrow(&ch_clone);
+        if !channel.active_with(&peer_addresses(&RefCell::borrow(&peers_clone))) {
+            return inactive();
+        }
+        is_clone
+            .borrow_mut()
+            .set_refusal(refusal(mode_clone.get(), channel.direction));
+        let (clip, val) = is_clone.borrow_mut().process_write(bytes);
+        if verbose >= 2 {
+            eprintln!("Received message: {:?}", bytes);
+            eprintln!("write_char.write_callback(): replying with: {:?}", val);
+        }
+        if let Some(rej) = is_clone.borrow_mut().take_rejection() {
+            report_rejection(
+                &rej,
+                &RefCell::borrow(&peers_clone),
+                &mut failures_clone.borrow_mut(),
+                RefCell::borrow(&conf_clone)
+                    .inbound_for(&channel)
+                    .error_threshold,
+            );
+            sum_clone
+                .borrow_mut()
+                .record_inbound_error(rej.error.name());
+        }
+        if let Some(clip) = clip {
+            for peer in RefCell::borrow(&peers_clone).iter() {
+                failures_clone.borrow_mut().remove(peer_address(peer));
+            }
+            // the remote clip is newer than any local one still waiting
+            pending_clone.borrow_mut().clear();
+            let origin = peer_addresses(&RefCell::borrow(&peers_clone)).join(",");
+            let local = convert_received(
+                &clip,
+                &RefCell::borrow(&conf_clone),
+                &mut echo_clone.borrow_mut(),
+                &origin,
+            );
+            if let Some(local) = local {
+                update_clipboard(&local, channel.selection).ok();
+                remote_clone.replace(Some(local));
+            }
+            println!("Updading clipboard with new remote clip: {:?}", clip);
+            //lw_clone.replace(clip);
+            record_stats(&sum_clone, &mut os_clone.borrow_mut(), true);
+            let flow = RefCell::borrow(&conf_clone).flow.clone();
+            os_clone.replace(OutSyncer::with_config(clip, verbose, flow));
+        }
+        Ok((Some(ValOrFn::Value(val)), true))
+    }));
+
+    let mut ver_desc = LocalDescBase::new(ver_uuid, ver_flags);
+    ver_desc.vf = ValOrFn::Value([0, 1][..].into());
+    ver_desc.set_handle(handles.write_ver);
+    write_char.add_desc(ver_desc);
+    copy_service.add_char(write_char);
+    /*
+    let mut write_serv = copy_service.get_char(&write_uuid);
+    write_serv.write_val_or_fn(&mut ValOrFn::Value(v, l));*/
+    copy_service
+}
+
+/// Connects to bluetoothd without registering anything.
+fn connect(bus_name: &str, verbose: u8, no_filter: bool) -> Result<Bluetooth, DaemonError> {
+    if !adapter_present() {
+        return Err(DaemonError::NoAdapter);
+    }
+    if rfkill_blocked() {
+        return Err(DaemonError::Rfkill);
+    }
+    let mut blue = Bluetooth::new(bus_name.to_string(), "/org/bluez/hci0".to_string())
+        .map_err(|e| DaemonError::from_bluez("connect to bluetoothd", &e))?;
+    blue.verbose = verbose;
+    if no_filter {
+        blue.set_filter(None)
+            .map_err(|e| DaemonError::from_bluez("set the D-Bus filter", &e))?;
+    }
+    Ok(blue)
+}
+fn get_hostname() -> Result<String, DaemonError> {
+    let res = Command::new("hostname")
+        .output()
+        .map_err(|e| DaemonError::Hostname(e.to_string()))?;
+    let n = String::from_utf8(res.stdout)
+        .map_err(|_| DaemonError::Hostname("not valid UTF-8".to_string()))?;
+    let n = n.trim();
+    if n.is_empty() {
+        return Err(DaemonError::Hostname("empty hostname".to_string()));
+    }
+    Ok(n.to_string())
+}
+/// Runs the checks done at startup without registering the service.
+fn preflight(bus_name: &str, verbose: u8, no_filter: bool) -> Result<(), DaemonError> {
+    if var_os("HOME").is_none() {
+        return Err(DaemonError::NoHome);
+    }
+    connect(bus_name, verbose, no_filter).map(|_| ())
+}
+fn fail(e: DaemonError) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(e.exit_code());
+}
+/// Connects to bluetoothd and registers the services of all channels.
+/// The handles of the channels are updated with the handles that were assigned.
+fn start_session(shared: &Shared, no_filter: bool) -> Result<Bluetooth, DaemonError> {
+    let mut blue = loop {
+        let mut blue = connect(&shared.bus_name, shared.verbose, no_filter)?;
+        for channel in &shared.channels {
+            blue.add_service(build_service(shared, channel))
+                .map_err(|e| DaemonError::from_bluez("add the GATT service", &e))?;
+        }
         let e = match blue.register_application() {
-            Ok(_) => break (in_syncer, out_syncer),
+            Ok(_) => break blue,
             Err(e) => e,
         };
-        if handles == Handles::default() {
-            panic!("Failed to register_application with zeroed handles: {:?}", e);
+        let zeroed = shared
+            .channels
+            .iter()
+            .all(|ch| *RefCell::borrow(&ch.handles) == Handles::default());
+        if zeroed {
+            return Err(
+                match DaemonError::from_bluez("register the application", &e) {
+                    DaemonError::Bluetooth(_, msg) => DaemonError::Registration(msg),
+                    e => e,
+                },
+            );
         } else {
-            eprintln!("Failed to register_application!: {:?}\nTrying getting new handles.", e);
-            handles = Handles::default();
+            eprintln!(
+                "Failed to register_application!: {:?}\nTrying getting new handles.",
+                e
+            );
+            for channel in &shared.channels {
+                channel.handles.replace(Handles::default());
+            }
         }
     };
 
-    let mut adv = Advertisement::new(AdType::Peripheral, name);
-    adv.duration = 2;
-    adv.timeout = std::u16::MAX;
-    adv.service_uuids.push(serv_uuid.clone());
     blue.set_power(true)
-        .expect("Failed to power on bluetooth controller!");
-    blue.set_discoverable(true)
-        .expect("Failed to make device discoverable!");
-    let adv_idx = match blue.start_adv(adv) {
-        Ok(idx) => idx,
-        Err((idx, _)) => {
-            eprintln!("Warning: failed to start advertisement");
-            idx
-        }
-    };
+        .map_err(|e| DaemonError::from_bluez("power on the adapter", &e))?;
+    let discoverable = RefCell::borrow(&shared.config).advert.discoverable;
+    blue.set_discoverable(discoverable)
+        .map_err(|e| DaemonError::from_bluez("set the adapter discoverable", &e))?;
 
     // Get new handles back
-    let mut serv = blue.get_service(&serv_uuid).unwrap();
-    let serv_handle = serv.handle();
-    let mut read_char = serv.get_child(&read_uuid).unwrap();
-    let read_ver = read_char.get_child(&ver_uuid).unwrap().handle();
-    let read_handle = read_char.handle();
-    let mut write_char = serv.get_child(&write_uuid).unwrap();
-    let write_ver = write_char.get_child(&ver_uuid).unwrap().handle();
-    let write_handle = write_char.handle();
-    let new_handles = Handles {
-        service: serv_handle,
-        read: read_handle,
-        read_ver,
-        write: write_handle,
-        write_ver,
+    let missing = |uuid: &UUID| {
+        DaemonError::Registration(format!(
+            "{} is missing from the registered application",
+            uuid
+        ))
     };
+    for channel in &shared.channels {
+        let uuids = &channel.uuids;
+        let mut serv = blue
+            .get_service(&uuids.service)
+            .ok_or_else(|| missing(&uuids.service))?;
+        let serv_handle = serv.handle();
+        let mut read_char = serv
+            .get_child(&uuids.read)
+            .ok_or_else(|| missing(&uuids.read))?;
+        let read_ver = read_char
+            .get_child(&uuids.ver)
+            .ok_or_else(|| missing(&uuids.ver))?
+            .handle();
+        let read_handle = read_char.handle();
+        let mut write_char = serv
+            .get_child(&uuids.write)
+            .ok_or_else(|| missing(&uuids.write))?;
+        let write_ver = write_char
+            .get_child(&uuids.ver)
+            .ok_or_else(|| missing(&uuids.ver))?
+            .handle();
+        let write_handle = write_char.handle();
+        let new_handles = Handles {
+            service: serv_handle,
+            read: read_handle,
+            read_ver,
+            write: write_handle,
+            write_ver,
+        };
 
-    if handles != new_handles {
-        eprintln!("Handles changed: {:?}\nWriting back.", new_handles);
-        if let Err(e) = set_handles::<&Path>(None, &new_handles) {
-            eprintln!("Failed to writeback handles!: {:?}", e);
+        if *RefCell::borrow(&channel.handles) != new_handles {
+            eprintln!("Handles changed: {:?}\nWriting back.", new_handles);
+            if let Err(e) = set_handles(&channel.handles_path, &new_handles) {
+                eprintln!("Failed to writeback handles!: {:?}", e);
+            }
+            channel.handles.replace(new_handles);
         }
     }
-
-    let mut target = Instant::now();
-    loop {
-        // check for writes to local clipboard from GATT client
-        let now = Instant::now();
-        blue.process_requests().unwrap();
-        let mut serv = blue.get_service(&serv_uuid).unwrap();
-        let mut write_char = serv.get_child(&write_uuid).unwrap();
-        write_char.check_write_fd();
-
-        // check for the read characteristic
-        let mut read_char = serv.get_child(&read_uuid).unwrap();
-        read_char.check_write_fd();
-        let mut os_bor = out_syncer.borrow_mut();
-        if let Err(e) = os_bor.indicate_local(&mut read_char) {
-            eprintln!("Error indicating: {:?}", e);
-        }
-        drop(os_bor);
-
-        // check for local updates to clipboard;
-        if let None = target.checked_duration_since(now) {
-            target = now + Duration::from_secs(2);
-            let new_clip = match get_clipboard() {
-                Ok(o) => o,
-                Err(e) => {
-                    if verbose > 0 {
-                        eprintln!("Failed to read clipboard: {:?}", e);
-                    }
-                    continue;
-                }
-            };
-            if RefCell::borrow(&out_syncer).get_clip() != &*new_clip {
-                println!("Clipboard changed, pushing changes: {:?}", new_clip);
-                in_syncer.borrow_mut().update_with_local(new_clip.clone());
-                out_syncer.replace(OutSyncer::new(new_clip, verbose));
-            }
-            match blue.restart_adv(adv_idx) {
+    Ok(blue)
+}
+/// Removes the advertisement so BlueZ doesn't keep advertising a service nobody is serving.
+///
+/// The application is unregistered by BlueZ once `blue` is dropped and its D-Bus connection
+/// closes, rustable doesn't implement unregistering it explicitly.
+fn end_session(mut blue: Bluetooth, advertiser: &mut Advertiser) {
+    advertiser.stop(&mut blue);
+}
+/// Sets the advertising parameters.
+fn apply_adv_config(adv: &mut Advertisement, cfg: &AdvertConfig) {
+    adv.duration = cfg.duration;
+    adv.timeout = cfg.timeout;
+}
+/// Starts and stops the advertisement according to the advertising config.
+struct Advertiser {
+    name: String,
+    service_uuid: UUID,
+    idx: Option<u16>,
+    /// Whether the running advertisement is discoverable.
+    discoverable: bool,
+    window_end: Option<Instant>,
+    pairing_end: Option<Instant>,
+}
+impl Advertiser {
+    fn new(name: String, service_uuid: UUID) -> Self {
+        Self {
+            name,
+            service_uuid,
+            idx: None,
+            discoverable: false,
+            window_end: None,
+            pairing_end: None,
+        }
+    }
+    /// Allows advertising for `dur` in window mode.
+    /// A pairing window also makes the adapter discoverable.
+    fn open_window(&mut self, dur: Duration, now: Instant, pairing: bool) {
+        self.window_end = Some(now + dur);
+        if pairing {
+            self.pairing_end = Some(now + dur);
+        }
+    }
+    fn pairing(&self, now: Instant) -> bool {
+        self.pairing_end.is_some_and(|end| now < end)
+    }
+    fn wanted(&self, cfg: &AdvertConfig, connected: bool, now: Instant) -> bool {
+        if connected && cfg.stop_when_connected {
+            return false;
+        }
+        match cfg.mode {
+            AdvertMode::Always => true,
+            AdvertMode::Window => self.window_end.is_some_and(|end| now < end),
+        }
+    }
+    /// Starts, restarts or stops the advertisement.
+    /// An error means the adapter could not be powered on again.
+    fn update(
+        &mut self,
+        blue: &mut Bluetooth,
+        cfg: &AdvertConfig,
+        connected: bool,
+        now: Instant,
+    ) -> Result<(), BLEError> {
+        let wanted = self.wanted(cfg, connected, now);
+        let mut cfg = cfg.clone();
+        cfg.discoverable |= self.pairing(now);
+        if self.idx.is_some() && self.discoverable != cfg.discoverable {
+            // the advertisement has to be recreated to change its flags
+            self.stop(blue);
+        }
+        match self.idx {
+            Some(_) if !wanted => self.stop(blue),
+            Some(idx) => match blue.restart_adv(idx) {
                 Ok(v) => {
                     if v {
-                        if let Err(e) = blue.set_discoverable(true) {
+                        // the advertisement stops if the adapter lost power
+                        blue.set_power(true)?;
+                        if let Err(e) = blue.set_discoverable(cfg.discoverable) {
                             eprintln!("Failed to set to discoverable: {:?}", e);
                         }
                     }
                 }
                 Err(e) => {
-                    if verbose > 1 {
+                    if verbose() > 1 {
                         eprintln!("Failed to set to started advertisement: {:?}", e);
                     }
                 }
+            },
+            None if wanted => {
+                if let Err(e) = blue.set_discoverable(cfg.discoverable) {
+                    eprintln!("Failed to set to discoverable: {:?}", e);
+                }
+                let mut adv = Advertisement::new(AdType::Peripheral, self.name.clone());
+                apply_adv_config(&mut adv, &cfg);
+                self.discoverable = cfg.discoverable;
+                adv.service_uuids.push(self.service_uuid.clone());
+                self.idx = Some(match blue.start_adv(adv) {
+                    Ok(idx) => idx,
+                    Err((idx, _)) => {
+                        eprintln!("Warning: failed to start advertisement");
+                        idx
+                    }
+                });
+                if verbose() > 0 {
+                    println!("Started advertising.");
+                }
+            }
+            None => (),
+        }
+        Ok(())
+    }
+    fn stop(&mut self, blue: &mut Bluetooth) {
+        if let Some(idx) = self.idx.take() {
+            if let Err(e) = blue.remove_adv(idx) {
+                eprintln!("Failed to remove advertisement: {:?}", e);
+            }
+            if verbose() > 0 {
+                println!("Stopped advertising.");
+            }
+        }
+    }
+    fn status(&self, now: Instant) -> String {
+        let mut ret = format!(
+            "advertising: {}",
+            if self.idx.is_some() { "yes" } else { "no" }
+        );
+        if let Some(end) = self.window_end.filter(|end| now < *end) {
+            ret.push_str(&format!(", window open for {}s", (end - now).as_secs()));
+        }
+        if self.pairing(now) {
+            ret.push_str(", pairing");
+        }
+        ret.push('\n');
+        ret
+    }
+}
+fn spawn_agent(cfg: &PairingConfig) -> Option<PairingAgent> {
+    if !cfg.agent {
+        return None;
+    }
+    match PairingAgent::spawn(&cfg.capability) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            eprintln!("Failed to start pairing agent: {:?}", e);
+            None
+        }
+    }
+}
+fn handle_agent_event(ev: AgentEvent, notify: bool) {
+    let msg = match ev {
+        AgentEvent::Passkey(passkey) => {
+            format!("Enter passkey {} on the other device to pair.", passkey)
+        }
+        AgentEvent::Confirm { passkey, accepted } => {
+            if accepted {
+                format!(
+                    "Confirmed passkey {}, check it matches the other device.",
+                    passkey
+                )
+            } else {
+                format!(
+                    "Rejected pairing with passkey {}, no pairing window is open.",
+                    passkey
+                )
             }
         }
-        sleep((now + Duration::from_millis(200)).saturating_duration_since(Instant::now()));
+        AgentEvent::Paired(addr) => format!("Paired with {}, it is now trusted.", addr),
+    };
+    println!("{}", msg);
+    if notify {
+        Command::new("notify-send")
+            .arg("Airboard")
+            .arg(&msg)
+            .status()
+            .ok();
+    }
+}
+/// Handles the pending signals. Returns `true` if the daemon should shut down.
+fn handle_signals(
+    signals: &mut Option<Signals>,
+    config_path: Option<&PathBuf>,
+    shared: &Shared,
+) -> bool {
+    let signals = match signals {
+        Some(s) => s,
+        None => return false,
+    };
+    for sig in signals.pending() {
+        match sig {
+            Signal::SIGHUP => match Config::load(config_path) {
+                Ok(c) => {
+                    println!("Reloaded configuration.");
+                    let new_channels = c.channels();
+                    for channel in &shared.channels {
+                        let name = channel.name();
+                        match new_channels.iter().find(|ch| ch.name == name) {
+                            Some(ch) => {
+                                channel.in_syncer.borrow_mut().set_config(c.inbound_for(ch));
+                                channel.config.replace(ch.clone());
+                            }
+                            None => eprintln!(
+                                "Channel {:?} was removed, restart the daemon to apply it.",
+                                name
+                            ),
+                        }
+                    }
+                    if new_channels.len() > shared.channels.len() {
+                        eprintln!("Channels were added, restart the daemon to apply them.");
+                    }
+                    if let Ok(filter) = SensitiveFilter::new(&c.sensitive) {
+                        shared.sensitive.replace(filter);
+                    }
+                    shared.config.replace(c);
+                }
+                Err(e) => eprintln!("Failed to reload config, keeping the old one: {}", e),
+            },
+            _ => {
+                println!("Received {:?}, shutting down.", sig);
+                return true;
+            }
+        }
+    }
+    false
+}
+/// Replies to the control commands that don't need a session with bluetoothd.
+fn control_reply(cmd: &str, shared: &Shared, advertiser: &Advertiser, now: Instant) -> String {
+    match cmd.split_whitespace().next().unwrap_or("") {
+        "status" => {
+            let mut ret = String::new();
+            for channel in &shared.channels {
+                let ch = RefCell::borrow(&channel.config);
+                ret.push_str(&format!(
+                    "channel {:?} ({:?}, {:?}, {}):\n",
+                    ch.name, ch.selection, ch.direction, channel.uuids.service
+                ));
+                ret.push_str(&status_report(&RefCell::borrow(&channel.out_syncer)));
+                if let Some(at) = channel.expires_at(shared) {
+                    let left = at.saturating_duration_since(now);
+                    ret.push_str(&format!("expires in: {}s\n", left.as_secs()));
+                }
+                let waiting = RefCell::borrow(&channel.pending).len();
+                if waiting > 0 {
+                    ret.push_str(&format!("waiting clips: {}\n", waiting));
+                }
+            }
+            ret.push_str(&RefCell::borrow(&shared.summary).to_string());
+            ret.push_str(&format!("sync: {:?}\n", shared.mode.get()));
+            ret.push_str(&advertiser.status(now));
+            ret
+        }
+        "metrics" => RefCell::borrow(&shared.summary).to_prometheus(),
+        "pause" => {
+            shared.mode.set(SyncMode::Paused);
+            "Sync paused.\n".to_string()
+        }
+        "resume" => {
+            shared.mode.set(SyncMode::Active);
+            "Sync resumed.\n".to_string()
+        }
+        "toggle" => match shared.mode.get() {
+            SyncMode::Active => {
+                shared.mode.set(SyncMode::Paused);
+                "Sync paused.\n".to_string()
+            }
+            _ => {
+                shared.mode.set(SyncMode::Active);
+                "Sync resumed.\n".to_string()
+            }
+        },
+        "once" => {
+            shared.mode.set(SyncMode::Once);
+            "Sending the next copy only.\n".to_string()
+        }
+        _ => format!("Unknown command: {}\n", cmd),
+    }
+}
+/// Ways a session with bluetoothd can end.
+enum SessionEnd {
+    Shutdown,
+    Lost,
+}
+/// Reports that the GATT objects of a channel are gone, so the session is restarted.
+fn service_lost(uuids: &Uuids) -> SessionEnd {
+    eprintln!(
+        "GATT service {} is missing, registering it again.",
+        uuids.service
+    );
+    SessionEnd::Lost
+}
+/// Doubles the delay between attempts to restore the bluetooth service, up to 30 seconds.
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(Duration::from_secs(30))
+}
+
+fn main() {
+    let parser = parser();
+    let args = parser.get_matches();
+    let config_path = args.value_of_os("config").map(PathBuf::from);
+    let config = match Config::load(config_path.as_ref()) {
+        Ok(c) => Rc::new(RefCell::new(c)),
+        Err(e) => fail(DaemonError::Config(e)),
+    };
+    let service = RefCell::borrow(&config).service.clone();
+    let socket_path = match args.value_of_os("socket") {
+        Some(p) => PathBuf::from(p),
+        None => match default_socket_path(&service.instance_file("airboard")) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Failed to find a directory for the control socket: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let cmd = match args.subcommand() {
+        ("status", Some(sub)) if sub.is_present("metrics") => Some("metrics".to_string()),
+        ("status", _) => Some("status".to_string()),
+        (name @ "pause", _) | (name @ "resume", _) | (name @ "toggle", _) | (name @ "once", _) => {
+            Some(name.to_string())
+        }
+        (name @ "advertise", Some(sub)) | (name @ "pair", Some(sub)) => {
+            match sub.value_of("minutes") {
+                Some(m) => Some(format!("{} {}", name, m)),
+                None => Some(name.to_string()),
+            }
+        }
+        _ => None,
+    };
+    if let Some(cmd) = cmd {
+        match send_command(&socket_path, &cmd) {
+            Ok(reply) => print!("{}", reply),
+            Err(e) => {
+                eprintln!("Failed to contact airboard daemon: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let stats_file = args.value_of_os("stats-file").map(PathBuf::from);
+    let name = match args.value_of("hostname") {
+        Some(n) => n.to_string(),
+        None => get_hostname().unwrap_or_else(|e| fail(e)),
+    };
+    let verbose = args.occurrences_of("verbose") as u8;
+    set_verbose(verbose);
+    let no_filter = args.is_present("no-filter");
+    let bus_name = service.bus_name();
+    if let Err(e) = preflight(&bus_name, verbose, no_filter) {
+        fail(e);
+    }
+    if args.is_present("check") {
+        println!("All checks passed, advertising as {:?}.", name);
+        return;
+    }
+    // the patterns were checked when the config was loaded
+    let sensitive = SensitiveFilter::new(&RefCell::borrow(&config).sensitive).unwrap();
+    let channels = RefCell::borrow(&config)
+        .channels()
+        .into_iter()
+        .map(|ch| Channel::new(&RefCell::borrow(&config), ch, &sensitive, verbose))
+        .collect();
+    let shared = Shared {
+        config,
+        summary: Rc::new(RefCell::new(StatsSummary::default())),
+        peers: Rc::new(RefCell::new(Vec::new())),
+        failures: Rc::new(RefCell::new(HashMap::new())),
+        verbose,
+        bus_name,
+        sensitive: RefCell::new(sensitive),
+        mode: Rc::new(Cell::new(SyncMode::Active)),
+        channels,
+    };
+    let (config, summary, peers) = (&shared.config, &shared.summary, &shared.peers);
+    let mut blue = start_session(&shared, no_filter).unwrap_or_else(|e| fail(e));
+    // only the first service fits in the advertisement
+    let mut advertiser = Advertiser::new(name, shared.channels[0].uuids.service.clone());
+
+    let mut control = match ControlServer::bind(&socket_path) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            eprintln!("Failed to bind control socket {:?}: {:?}", socket_path, e);
+            None
+        }
+    };
+
+    let mut watches: Vec<LocalWatch> = shared.channels.iter().map(LocalWatch::new).collect();
+    let mut signals = match Signals::install() {
+        Ok(s) => Some(s),
+        Err(e) => {
+            eprintln!("Failed to install signal handlers: {:?}", e);
+            None
+        }
+    };
+    let mut clip_deadline = Instant::now();
+    'session: loop {
+        let mut adv_deadline = Instant::now();
+        let mut agent = spawn_agent(&RefCell::borrow(config).pairing);
+        let end = 'main: loop {
+            // wait for D-Bus messages, client writes, clipboard changes or the next timer
+            let mut deadline = adv_deadline;
+            for watch in &watches {
+                if watch.polling {
+                    deadline = deadline.min(clip_deadline);
+                }
+                if let Some(settle_at) = watch.settle_at {
+                    deadline = deadline.min(settle_at);
+                }
+            }
+            for channel in &shared.channels {
+                if let Some(d) = RefCell::borrow(&channel.out_syncer).next_deadline() {
+                    deadline = deadline.min(d);
+                }
+                if let Some(d) = channel.expires_at(&shared) {
+                    deadline = deadline.min(d);
+                }
+            }
+            let mut fds = vec![PollFd::new(dbus_fd(&blue), PollFlags::POLLIN)];
+            for channel in &shared.channels {
+                let uuids = &channel.uuids;
+                let mut serv = match blue.get_service(&uuids.service) {
+                    Some(s) => s,
+                    None => break 'main service_lost(uuids),
+                };
+                for uuid in &[&uuids.read, &uuids.write] {
+                    match serv.get_child(*uuid) {
+                        Some(c) => {
+                            if let Some(fd) = char_write_fd(&c) {
+                                fds.push(PollFd::new(fd, PollFlags::POLLIN));
+                            }
+                        }
+                        None => break 'main service_lost(uuids),
+                    }
+                }
+            }
+            let watch_start = fds.len();
+            for w in watches.iter().filter_map(|w| w.watcher.as_ref()) {
+                fds.push(PollFd::new(w.as_raw_fd(), PollFlags::POLLIN));
+            }
+            if let Some(control) = &control {
+                fds.push(PollFd::new(control.as_raw_fd(), PollFlags::POLLIN));
+            }
+            if let Some(signals) = &signals {
+                fds.push(PollFd::new(signals.as_raw_fd(), PollFlags::POLLIN));
+            }
+            let agent_idx = agent.as_ref().map(|a| {
+                fds.push(PollFd::new(a.as_raw_fd(), PollFlags::POLLIN));
+                fds.len() - 1
+            });
+            let timeout = deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis()
+                + 1;
+            match poll(&mut fds, timeout.min(i32::MAX as u128) as i32) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(e) => eprintln!("Failed to poll: {:?}", e),
+            }
+            let now = Instant::now();
+
+            if handle_signals(&mut signals, config_path.as_ref(), &shared) {
+                break 'main SessionEnd::Shutdown;
+            }
+
+            // check for writes to local clipboard from GATT client
+            if let Err(e) = blue.process_requests() {
+                eprintln!("Failed to process D-Bus requests: {:?}", e);
+                break 'main SessionEnd::Lost;
+            }
+            for channel in &shared.channels {
+                let uuids = &channel.uuids;
+                let mut serv = match blue.get_service(&uuids.service) {
+                    Some(s) => s,
+                    None => break 'main service_lost(uuids),
+                };
+                let mut write_char = match serv.get_child(&uuids.write) {
+                    Some(c) => c,
+                    None => break 'main service_lost(uuids),
+                };
+                if let Err(e) = write_char.check_write_fd() {
+                    eprintln!("Failed to read client writes: {:?}", e);
+                }
+
+                // check for the read characteristic and send chunks as soon as ACKs arrive
+                let mut read_char = match serv.get_child(&uuids.read) {
+                    Some(c) => c,
+                    None => break 'main service_lost(uuids),
+                };
+                if let Err(e) = read_char.check_write_fd() {
+                    eprintln!("Failed to read client ACKs: {:?}", e);
+                }
+                if !channel.direction().sends() || !channel.active(&RefCell::borrow(peers)) {
+                    continue;
+                }
+                let mut os_bor = channel.out_syncer.borrow_mut();
+                if let Some(mtu) = negotiated_mtu(&read_char) {
+                    os_bor.set_mtu(mtu);
+                }
+                if let Err(e) = os_bor.indicate_local(&mut read_char) {
+                    eprintln!("Error indicating: {:?}", e);
+                }
+                if record_stats(summary, &mut os_bor, false) {
+                    if let Some(path) = &stats_file {
+                        if let Err(e) = write_stats_file(path, &RefCell::borrow(summary)) {
+                            eprintln!("Failed to write stats file: {:?}", e);
+                        }
+                    }
+                }
+                drop(os_bor);
+                channel.advance(&shared);
+            }
+
+            if let Some(control) = &mut control {
+                let res = control.process(|cmd| {
+                    let mut words = cmd.split_whitespace();
+                    match words.next().unwrap_or("") {
+                        "advertise" => {
+                            let minutes = match words.next().map(|w| w.parse::<u32>()) {
+                                Some(Ok(m)) => m,
+                                Some(Err(_)) => return format!("Invalid minutes: {}\n", cmd),
+                                None => RefCell::borrow(config).advert.window_minutes,
+                            };
+                            let dur = Duration::from_secs(minutes as u64 * 60);
+                            advertiser.open_window(dur, now, false);
+                            adv_deadline = now;
+                            format!("Advertising for {} minutes.\n", minutes)
+                        }
+                        "pair" => {
+                            let minutes = match words.next().map(|w| w.parse::<u32>()) {
+                                Some(Ok(m)) => m,
+                                Some(Err(_)) => return format!("Invalid minutes: {}\n", cmd),
+                                None => RefCell::borrow(config).pairing.window_minutes,
+                            };
+                            let dur = Duration::from_secs(minutes as u64 * 60);
+                            advertiser.open_window(dur, now, true);
+                            adv_deadline = now;
+                            match &mut agent {
+                                Some(agent) => match agent.open_window(dur, now) {
+                                    Ok(_) => format!("Pairable for {} minutes.\n", minutes),
+                                    Err(e) => format!("Failed to open pairing window: {}\n", e),
+                                },
+                                None => format!(
+                                    "Discoverable for {} minutes, pairing is left to the desktop agent.\n",
+                                    minutes
+                                ),
+                            }
+                        }
+                        _ => control_reply(cmd, &shared, &advertiser, now),
+                    }
+                });
+                if let Err(e) = res {
+                    eprintln!("Control socket error: {:?}", e);
+                }
+            }
+
+            // queued requests and newly paired devices are also checked with the timers
+            let agent_ready =
+                agent_idx.is_some_and(|idx| fds[idx].revents().is_some_and(|r| !r.is_empty()));
+            if let Some(a) = &mut agent {
+                if agent_ready || now >= adv_deadline {
+                    match a.process(now) {
+                        Ok(events) => {
+                            let notify = RefCell::borrow(config).pairing.notify;
+                            for ev in events {
+                                handle_agent_event(ev, notify);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Pairing agent failed: {}", e);
+                            agent = None;
+                        }
+                    }
+                }
+            }
+
+            for channel in &shared.channels {
+                channel.check_expiry(&shared, now);
+            }
+
+            // check for local updates to clipboard;
+            let poll_clips = now >= clip_deadline;
+            if poll_clips {
+                clip_deadline = now + Duration::from_secs(2);
+            }
+            let mut fd_idx = watch_start;
+            for (channel, watch) in shared.channels.iter().zip(watches.iter_mut()) {
+                if let Some(w) = &mut watch.watcher {
+                    if fds[fd_idx].revents().is_some_and(|r| !r.is_empty()) {
+                        // restart the delay on every change, until the selection settles
+                        let settle =
+                            RefCell::borrow(config).settle_for(&RefCell::borrow(&channel.config));
+                        watch.settle_at = Some(now + settle);
+                        if !w.drain() {
+                            eprintln!("Clipboard watcher exited, polling instead.");
+                            watch.watcher = None;
+                            watch.polling = true;
+                        }
+                    }
+                    fd_idx += 1;
+                } else if watch.polling && poll_clips {
+                    watch.settle_at = Some(now);
+                }
+                match watch.settle_at {
+                    Some(settle_at) if now >= settle_at => watch.settle_at = None,
+                    _ => continue,
+                }
+                let res = get_clipboard(
+                    channel.selection(),
+                    &RefCell::borrow(config),
+                    &RefCell::borrow(&shared.sensitive),
+                );
+                match res {
+                    Ok(new_clip) => {
+                        let echo = channel
+                            .echo
+                            .borrow_mut()
+                            .echo_of(&new_clip, now)
+                            .map(|o| o.to_owned());
+                        if let Some(origin) = echo {
+                            if verbose > 0 {
+                                eprintln!(
+                                    "Ignoring clipboard change, it is a received clip from {:?}: {:?}",
+                                    origin, new_clip
+                                );
+                            }
+                        } else if RefCell::borrow(&channel.out_syncer).get_clip() != &*new_clip
+                            && **RefCell::borrow(&channel.local) != *new_clip
+                        {
+                            match shared.mode.get() {
+                                SyncMode::Active => (),
+                                SyncMode::Paused => {
+                                    if verbose > 0 {
+                                        eprintln!("Sync paused, not sending: {:?}", new_clip);
+                                    }
+                                    // remember it, so it isn't sent on resume
+                                    channel.local.replace(new_clip.clone());
+                                    channel.in_syncer.borrow_mut().update_with_local(new_clip);
+                                    continue;
+                                }
+                                SyncMode::Once => {
+                                    println!("Sending this copy only, sync is paused again.");
+                                    shared.mode.set(SyncMode::Paused);
+                                }
+                            }
+                            println!("Clipboard changed, pushing changes: {:?}", new_clip);
+                            channel.echo.borrow_mut().clear();
+                            channel
+                                .in_syncer
+                                .borrow_mut()
+                                .update_with_local(new_clip.clone());
+                            channel.push_local(&shared, new_clip);
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                        println!("Not sending clipboard change, {}.", e);
+                    }
+                    Err(e) => {
+                        if verbose > 0 {
+                            eprintln!("Failed to read clipboard: {:?}", e);
+                        }
+                    }
+                }
+            }
+
+            if now >= adv_deadline {
+                adv_deadline = now + Duration::from_secs(2);
+                if !adapter_present() {
+                    eprintln!("Bluetooth adapter disappeared.");
+                    break 'main SessionEnd::Lost;
+                }
+                match connected_devices() {
+                    Ok(devices) => *peers.borrow_mut() = devices,
+                    Err(e) => {
+                        if verbose > 1 {
+                            eprintln!("Failed to list connected devices: {:?}", e);
+                        }
+                    }
+                }
+                let connected = shared
+                    .channels
+                    .iter()
+                    .any(|ch| client_subscribed(&mut blue, &ch.uuids));
+                let cfg = RefCell::borrow(config).advert.clone();
+                if let Err(e) = advertiser.update(&mut blue, &cfg, connected, now) {
+                    eprintln!("Failed to power on bluetooth controller: {:?}", e);
+                    break 'main SessionEnd::Lost;
+                }
+            }
+        };
+
+        drop(agent);
+        end_session(blue, &mut advertiser);
+        if let SessionEnd::Shutdown = end {
+            break;
+        }
+
+        // bluetoothd restarted or the adapter went away, wait for it to come back
+        eprintln!("Lost bluetooth service, waiting for bluetoothd and the adapter.");
+        for channel in &shared.channels {
+            let clip = RefCell::borrow(&channel.out_syncer).get_clip_rc();
+            channel.reset_out(&shared, clip);
+        }
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            // keep answering the control socket while waiting
+            let retry_at = Instant::now() + backoff;
+            loop {
+                let mut fds: Vec<PollFd> = signals
+                    .iter()
+                    .map(|s| s.as_raw_fd())
+                    .chain(control.iter().map(|c| c.as_raw_fd()))
+                    .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+                    .collect();
+                let timeout = retry_at.saturating_duration_since(Instant::now());
+                poll(&mut fds, timeout.as_millis() as i32 + 1).ok();
+                if handle_signals(&mut signals, config_path.as_ref(), &shared) {
+                    break 'session;
+                }
+                let now = Instant::now();
+                if let Some(control) = &mut control {
+                    let res = control.process(|cmd| match cmd.split_whitespace().next() {
+                        Some("advertise") | Some("pair") => {
+                            "Bluetooth is unavailable, try again once it is back.\n".to_string()
+                        }
+                        _ => control_reply(cmd, &shared, &advertiser, now),
+                    });
+                    if let Err(e) = res {
+                        eprintln!("Control socket error: {:?}", e);
+                    }
+                }
+                if now >= retry_at {
+                    break;
+                }
+            }
+            if adapter_present() {
+                match start_session(&shared, no_filter) {
+                    Ok(b) => {
+                        println!("Bluetooth service restored.");
+                        blue = b;
+                        break;
+                    }
+                    Err(e) => {
+                        if verbose > 0 {
+                            eprintln!("Failed to restart bluetooth service: {}", e);
+                        }
+                    }
+                }
+            }
+            backoff = next_backoff(backoff);
+        }
+    }
+
+    for channel in &shared.channels {
+        let name = channel.name();
+        if let Err(e) = save_partial(&RefCell::borrow(&channel.in_syncer), &service, &name) {
+            eprintln!("Failed to save unfinished transfer: {:?}", e);
+        }
+        record_stats(summary, &mut channel.out_syncer.borrow_mut(), true);
+    }
+    if let Some(path) = &stats_file {
+        if let Err(e) = write_stats_file(path, &RefCell::borrow(summary)) {
+            eprintln!("Failed to write stats file: {:?}", e);
+        }
     }
 }
 
@@ -476,4 +1942,311 @@ fn parser<'a, 'b>() -> App<'a, 'b> {
                 .value_name("NAME")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("stats-file")
+                .long("stats-file")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Writes transfer statistics to PATH in the Prometheus text format."),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Run the startup checks and exit."),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Path of the config file [default: ~/.config/airboard/config.yaml]."),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Path of the control socket."),
+        )
+        .subcommand(
+            SubCommand::with_name("advertise")
+                .about("Makes the running daemon advertise, for the window mode.")
+                .arg(
+                    Arg::with_name("minutes").value_name("MINUTES").help(
+                        "How long to advertise for [default: window_minutes from the config].",
+                    ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pair")
+                .about("Opens a pairing window on the running daemon.")
+                .arg(Arg::with_name("minutes").value_name("MINUTES").help(
+                    "How long to accept pairing for [default: window_minutes from the config].",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("pause")
+                .about("Stops syncing until resumed, devices writing clips get an error."),
+        )
+        .subcommand(SubCommand::with_name("resume").about("Resumes syncing after a pause."))
+        .subcommand(
+            SubCommand::with_name("toggle").about("Pauses or resumes syncing, e.g. from a tray."),
+        )
+        .subcommand(
+            SubCommand::with_name("once")
+                .about("Pauses syncing except for the next copy, for explicit sharing."),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Prints the transfer status of the running daemon.")
+                .arg(
+                    Arg::with_name("metrics")
+                        .long("metrics")
+                        .help("Prints the metrics in the Prometheus text format."),
+                ),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertiser() -> Advertiser {
+        let uuid = ServiceConfig::default().service_uuid.as_str().to_uuid();
+        Advertiser::new("test".into(), uuid)
+    }
+
+    fn test_shared(config: Config, channels: Vec<ChannelConfig>) -> Shared {
+        let sensitive = SensitiveFilter::new(&config.sensitive).unwrap();
+        let channels = channels
+            .into_iter()
+            .map(|ch| Channel {
+                in_syncer: Rc::new(RefCell::new(InSyncer::with_config(
+                    Rc::new(Clip::default()),
+                    config.inbound_for(&ch),
+                ))),
+                out_syncer: Rc::new(RefCell::new(OutSyncer::new(Rc::new(Clip::default()), 0))),
+                pending: Rc::new(RefCell::new(VecDeque::new())),
+                local: RefCell::new(Rc::new(Clip::default())),
+                echo: Rc::new(RefCell::new(EchoFilter::new(
+                    Duration::from_secs(config.echo.ttl_secs),
+                    config.echo.per_origin,
+                ))),
+                serving: RefCell::new(None),
+                last_remote: Rc::new(RefCell::new(None)),
+                uuids: Uuids::new(&config.service.channel_uuids(&ch)),
+                handles: RefCell::new(Handles::default()),
+                handles_path: PathBuf::new(),
+                config: Rc::new(RefCell::new(ch)),
+            })
+            .collect();
+        Shared {
+            config: Rc::new(RefCell::new(config)),
+            summary: Rc::new(RefCell::new(StatsSummary::default())),
+            peers: Rc::new(RefCell::new(Vec::new())),
+            failures: Rc::new(RefCell::new(HashMap::new())),
+            verbose: 0,
+            bus_name: String::new(),
+            sensitive: RefCell::new(sensitive),
+            mode: Rc::new(Cell::new(SyncMode::Active)),
+            channels,
+        }
+    }
+
+    fn text(s: &str) -> Rc<Clip> {
+        Rc::new(Clip::new(s.as_bytes().to_vec(), "text/plain".to_owned()))
+    }
+
+    /// Acknowledges `pos` bytes of the clip being sent on `channel`.
+    fn ack(channel: &Channel, pos: usize) {
+        let clip = RefCell::borrow(&channel.out_syncer).get_clip_rc();
+        let mut ack = (pos as u32).to_be_bytes().to_vec();
+        ack.extend_from_slice(&clip.hash());
+        channel.out_syncer.borrow_mut().update_pos(&ack);
+    }
+
+    fn sending(channel: &Channel) -> Rc<Clip> {
+        RefCell::borrow(&channel.out_syncer).get_clip_rc()
+    }
+
+    #[test]
+    fn queued_clips_sent_in_order() {
+        let mut config = Config::default();
+        config.local.in_flight = InFlightPolicy::Queue;
+        let shared = test_shared(config, vec![ChannelConfig::default()]);
+        let channel = &shared.channels[0];
+        channel.push_local(&shared, text("first"));
+        // nothing has started yet, so the clip is sent right away
+        assert_eq!(sending(channel).data(), b"first");
+        ack(channel, 0);
+        channel.push_local(&shared, text("second"));
+        channel.push_local(&shared, text("third"));
+        channel.advance(&shared);
+        assert_eq!(sending(channel).data(), b"first");
+
+        ack(channel, 5);
+        channel.advance(&shared);
+        assert_eq!(sending(channel).data(), b"second");
+        // the client hasn't read the header of the second clip yet
+        channel.advance(&shared);
+        assert_eq!(sending(channel).data(), b"second");
+        ack(channel, 0);
+        ack(channel, 6);
+        channel.advance(&shared);
+        assert_eq!(sending(channel).data(), b"third");
+        assert!(RefCell::borrow(&channel.pending).is_empty());
+    }
+
+    #[test]
+    fn in_flight_policies() {
+        let mut config = Config::default();
+        config.local.in_flight = InFlightPolicy::Finish;
+        let shared = test_shared(config, vec![ChannelConfig::default()]);
+        let channel = &shared.channels[0];
+        channel.push_local(&shared, text("first"));
+        ack(channel, 0);
+        channel.push_local(&shared, text("second"));
+        channel.push_local(&shared, text("third"));
+        assert_eq!(sending(channel).data(), b"first");
+        ack(channel, 5);
+        channel.advance(&shared);
+        assert_eq!(sending(channel).data(), b"third");
+
+        RefCell::borrow_mut(&shared.config).local.in_flight = InFlightPolicy::Abort;
+        ack(channel, 0);
+        channel.push_local(&shared, text("fourth"));
+        assert_eq!(sending(channel).data(), b"fourth");
+        assert_eq!(RefCell::borrow(&shared.summary).last().unwrap().bytes, 5);
+    }
+
+    #[test]
+    fn restore_backoff() {
+        let mut backoff = Duration::from_secs(1);
+        let mut delays = Vec::new();
+        for _ in 0..7 {
+            backoff = next_backoff(backoff);
+            delays.push(backoff.as_secs());
+        }
+        assert_eq!(delays, [2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn advertising_wanted() {
+        let now = Instant::now();
+        let mut adv = advertiser();
+        let mut cfg = AdvertConfig::default();
+        assert!(adv.wanted(&cfg, false, now));
+        assert!(!adv.wanted(&cfg, true, now));
+        cfg.stop_when_connected = false;
+        assert!(adv.wanted(&cfg, true, now));
+
+        cfg.mode = AdvertMode::Window;
+        assert!(!adv.wanted(&cfg, false, now));
+        adv.open_window(Duration::from_secs(60), now, false);
+        assert!(adv.wanted(&cfg, false, now + Duration::from_secs(59)));
+        assert!(!adv.wanted(&cfg, false, now + Duration::from_secs(60)));
+        assert!(!adv.pairing(now));
+    }
+
+    #[test]
+    fn advertising_status() {
+        let now = Instant::now();
+        let mut adv = advertiser();
+        assert_eq!(adv.status(now), "advertising: no\n");
+        adv.open_window(Duration::from_secs(60), now, true);
+        adv.idx = Some(0);
+        assert_eq!(
+            adv.status(now + Duration::from_secs(30)),
+            "advertising: yes, window open for 30s, pairing\n"
+        );
+        assert_eq!(
+            adv.status(now + Duration::from_secs(60)),
+            "advertising: yes\n"
+        );
+    }
+
+    #[test]
+    fn received_clip_remembered_as_written() {
+        let mut config = Config::default();
+        config.text.normalise_line_endings = true;
+        let mut echo = EchoFilter::new(Duration::from_secs(30), 4);
+        let clip = Rc::new(Clip::new(b"a\r\nb".to_vec(), "text/plain".to_owned()));
+        let local = convert_received(&clip, &config, &mut echo, "phone").unwrap();
+        assert_eq!(local.data(), b"a\nb");
+        assert_eq!(echo.echo_of(&local, Instant::now()), Some("phone"));
+
+        let dir = std::env::temp_dir().join(format!("airboard-received-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hi").unwrap();
+        let files = Rc::new(files::pack(&[dir.join("a.txt")], 1024).unwrap());
+        config.files.receive = true;
+        config.files.download_dir = Some(dir.clone());
+        let local = convert_received(&files, &config, &mut echo, "phone").unwrap();
+        let saved = files::uri_list(&[dir.join("a (1).txt")]);
+        assert_eq!(local.data(), saved.as_bytes());
+        let read_back = Clip::new(saved.into_bytes(), "text/uri-list".to_owned());
+        assert_eq!(echo.echo_of(&read_back, Instant::now()), Some("phone"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn served_clip_expires() {
+        let mut config = Config::default();
+        config.expiry.ttl_secs = 10;
+        config.local.in_flight = InFlightPolicy::Queue;
+        let shared = test_shared(config, vec![ChannelConfig::default()]);
+        let channel = &shared.channels[0];
+        let now = Instant::now();
+        channel.push_local(&shared, text("secret"));
+        ack(channel, 0);
+        channel.push_local(&shared, text("queued"));
+        channel.check_expiry(&shared, now);
+        assert_eq!(
+            channel.expires_at(&shared),
+            Some(now + Duration::from_secs(10))
+        );
+        channel.check_expiry(&shared, now + Duration::from_secs(9));
+        assert_eq!(sending(channel).data(), b"secret");
+
+        channel.check_expiry(&shared, now + Duration::from_secs(10));
+        assert!(sending(channel).is_clear());
+        assert!(RefCell::borrow(&channel.pending).is_empty());
+        // the clear clip itself doesn't expire
+        channel.check_expiry(&shared, now + Duration::from_secs(30));
+        assert_eq!(channel.expires_at(&shared), None);
+    }
+
+    #[test]
+    fn clips_kept_without_ttl() {
+        let shared = test_shared(Config::default(), vec![ChannelConfig::default()]);
+        let channel = &shared.channels[0];
+        channel.push_local(&shared, text("kept"));
+        let now = Instant::now();
+        channel.check_expiry(&shared, now);
+        channel.check_expiry(&shared, now + Duration::from_secs(24 * 3600));
+        assert_eq!(sending(channel).data(), b"kept");
+    }
+
+    #[test]
+    fn inbound_refusals() {
+        assert_eq!(refusal(SyncMode::Active, Direction::Both), None);
+        assert_eq!(refusal(SyncMode::Active, Direction::Receive), None);
+        assert_eq!(
+            refusal(SyncMode::Paused, Direction::Both),
+            Some(InError::Paused)
+        );
+        assert_eq!(
+            refusal(SyncMode::Once, Direction::Both),
+            Some(InError::Paused)
+        );
+        assert_eq!(
+            refusal(SyncMode::Active, Direction::Send),
+            Some(InError::SendOnly)
+        );
+        assert_eq!(
+            refusal(SyncMode::Paused, Direction::Send),
+            Some(InError::SendOnly)
+        );
+    }
 }
@@ -0,0 +1,397 @@
+//! A BlueZ pairing agent, registered as an Agent1 object on the system bus.
+//!
+//! The agent is only registered, as the default agent, while a pairing window is open.
+//! Outside of the window every request goes to the agent of the desktop session, if any.
+//! Devices that pair during the window are marked as trusted.
+
+use rustbus::client_conn::Timeout;
+use rustbus::message_builder::MarshalledMessage;
+use rustbus::wire::marshal::traits::ObjectPath;
+use rustbus::wire::unmarshal::traits::Variant;
+use rustbus::{MessageBuilder, MessageType, RpcConn};
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+const AGENT_PATH: &str = "/io/maves/airboard/agent";
+const ADAPTER_PATH: &str = "/org/bluez/hci0";
+const BLUEZ_DEST: &str = "org.bluez";
+const MANAGER_IF: &str = "org.bluez.AgentManager1";
+const ADAPTER_IF: &str = "org.bluez.Adapter1";
+const DEVICE_IF: &str = "org.bluez.Device1";
+const PROPS_IF: &str = "org.freedesktop.DBus.Properties";
+const REJECTED: &str = "org.bluez.Error.Rejected";
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Requests and events of the agent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AgentEvent {
+    /// A passkey to show to the user, to be entered on the other device.
+    Passkey(String),
+    /// A passkey to compare with the other device, answered with `accepted`.
+    Confirm { passkey: String, accepted: bool },
+    /// A device bonded successfully and is now trusted.
+    Paired(String),
+}
+
+/// The answers to agent requests, kept apart from the connection.
+#[derive(Default)]
+struct AgentState {
+    window_end: Option<Instant>,
+    /// Object paths of the devices accepted during the window, and whether they are paired.
+    accepted: HashMap<String, bool>,
+}
+
+impl AgentState {
+    fn window_open(&self, now: Instant) -> bool {
+        self.window_end.is_some_and(|end| now < end)
+    }
+    /// Answers a call to the Agent1 interface. Requests are only accepted during the window.
+    fn answer(
+        &mut self,
+        call: &MarshalledMessage,
+        now: Instant,
+    ) -> (MarshalledMessage, Option<AgentEvent>) {
+        let accept = self.window_open(now);
+        let mut parser = call.body.parser();
+        let device = parser
+            .get::<ObjectPath>()
+            .ok()
+            .map(|p| p.as_ref().to_owned());
+        let member = call.dynheader.member.as_deref().unwrap_or("");
+        let ev = match (member, device) {
+            ("Release", _) | ("Cancel", _) => None,
+            ("DisplayPasskey", Some(device)) if accept => {
+                let passkey: u32 = match parser.get() {
+                    Ok(p) => p,
+                    Err(_) => return (reject(call, "Invalid passkey"), None),
+                };
+                // called again for every key entered on the other device
+                match self.accepted.insert(device, false) {
+                    Some(_) => None,
+                    None => Some(AgentEvent::Passkey(format!("{:06}", passkey))),
+                }
+            }
+            ("RequestConfirmation", Some(device)) => {
+                let passkey: u32 = match parser.get() {
+                    Ok(p) => p,
+                    Err(_) => return (reject(call, "Invalid passkey"), None),
+                };
+                let ev = AgentEvent::Confirm {
+                    passkey: format!("{:06}", passkey),
+                    accepted: accept,
+                };
+                if !accept {
+                    return (reject(call, "No pairing window is open"), Some(ev));
+                }
+                self.accepted.insert(device, false);
+                Some(ev)
+            }
+            ("RequestAuthorization", Some(device)) if accept => {
+                self.accepted.insert(device, false);
+                None
+            }
+            // only services of the devices paired during this window
+            ("AuthorizeService", Some(device)) if accept && self.accepted.contains_key(&device) => {
+                None
+            }
+            _ => return (reject(call, "Rejected by airboard"), None),
+        };
+        (call.dynheader.make_response(), ev)
+    }
+    /// Devices that were accepted but haven't paired yet.
+    fn unpaired(&self) -> Vec<String> {
+        self.accepted
+            .iter()
+            .filter(|(_, paired)| !**paired)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+fn reject(call: &MarshalledMessage, msg: &str) -> MarshalledMessage {
+    let mut reply = call
+        .dynheader
+        .make_error_response(REJECTED.to_owned(), Some(msg.to_owned()));
+    reply.typ = MessageType::Error;
+    reply
+}
+
+/// Returns the address of the device at the BlueZ object path `path`.
+fn device_address(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.strip_prefix("dev_").unwrap_or(name).replace('_', ":")
+}
+
+fn dbus_error<E: std::fmt::Debug>(e: E) -> Error {
+    Error::other(format!("{:?}", e))
+}
+
+fn method_call(path: &str, interface: &str, member: &str) -> MarshalledMessage {
+    MessageBuilder::new()
+        .call(member.to_owned())
+        .on(path.to_owned())
+        .with_interface(interface.to_owned())
+        .at(BLUEZ_DEST.to_owned())
+        .build()
+}
+
+pub struct PairingAgent {
+    conn: RpcConn,
+    capability: String,
+    registered: bool,
+    /// The pairable state of the adapter before the window was opened.
+    was_pairable: bool,
+    state: AgentState,
+}
+
+impl PairingAgent {
+    /// Connects to the system bus. The agent with `capability`, e.g. `DisplayOnly`
+    /// or `DisplayYesNo`, is registered once a window is opened.
+    pub fn spawn(capability: &str) -> std::io::Result<Self> {
+        let mut conn = RpcConn::system_conn(Timeout::Duration(CALL_TIMEOUT)).map_err(dbus_error)?;
+        // refuse calls to anything but the agent, and ignore signals
+        conn.set_filter(Box::new(|msg| match msg.typ {
+            MessageType::Call => msg.dynheader.object.as_deref() == Some(AGENT_PATH),
+            MessageType::Signal | MessageType::Invalid => false,
+            MessageType::Reply | MessageType::Error => true,
+        }));
+        Ok(Self {
+            conn,
+            capability: capability.to_owned(),
+            registered: false,
+            was_pairable: false,
+            state: AgentState::default(),
+        })
+    }
+    /// Calls a BlueZ method and waits for the reply.
+    fn call(&mut self, mut msg: MarshalledMessage) -> std::io::Result<MarshalledMessage> {
+        let timeout = Timeout::Duration(CALL_TIMEOUT);
+        let serial = self
+            .conn
+            .send_message(&mut msg, timeout)
+            .map_err(dbus_error)?;
+        let reply = self
+            .conn
+            .wait_response(serial, timeout)
+            .map_err(dbus_error)?;
+        match reply.typ {
+            MessageType::Error => Err(dbus_error(reply.dynheader.error_name)),
+            _ => Ok(reply),
+        }
+    }
+    fn get_bool(&mut self, path: &str, interface: &str, prop: &str) -> std::io::Result<bool> {
+        let mut msg = method_call(path, PROPS_IF, "Get");
+        msg.body.push_param2(interface, prop).map_err(dbus_error)?;
+        let reply = self.call(msg)?;
+        let var: Variant = reply.body.parser().get().map_err(dbus_error)?;
+        var.get().map_err(dbus_error)
+    }
+    fn set_bool(
+        &mut self,
+        path: &str,
+        interface: &str,
+        prop: &str,
+        val: bool,
+    ) -> std::io::Result<()> {
+        let mut msg = method_call(path, PROPS_IF, "Set");
+        msg.body.push_param2(interface, prop).map_err(dbus_error)?;
+        msg.body.push_variant(val).map_err(dbus_error)?;
+        self.call(msg).map(|_| ())
+    }
+    fn agent_call(&mut self, member: &str) -> std::io::Result<()> {
+        let mut msg = method_call("/org/bluez", MANAGER_IF, member);
+        msg.body
+            .push_param(ObjectPath::new(AGENT_PATH).unwrap())
+            .map_err(dbus_error)?;
+        if member == "RegisterAgent" {
+            msg.body
+                .push_param(self.capability.as_str())
+                .map_err(dbus_error)?;
+        }
+        self.call(msg).map(|_| ())
+    }
+    /// Registers the agent as the default agent and makes the adapter pairable for `dur`.
+    pub fn open_window(&mut self, dur: Duration, now: Instant) -> std::io::Result<()> {
+        if !self.registered {
+            self.agent_call("RegisterAgent")?;
+            self.registered = true;
+            self.agent_call("RequestDefaultAgent")?;
+            self.was_pairable = self.get_bool(ADAPTER_PATH, ADAPTER_IF, "Pairable")?;
+        }
+        self.state.window_end = Some(now + dur);
+        let mut msg = method_call(ADAPTER_PATH, PROPS_IF, "Set");
+        msg.body
+            .push_param2(ADAPTER_IF, "PairableTimeout")
+            .map_err(dbus_error)?;
+        msg.body
+            .push_variant(dur.as_secs() as u32)
+            .map_err(dbus_error)?;
+        self.call(msg)?;
+        self.set_bool(ADAPTER_PATH, ADAPTER_IF, "Pairable", true)
+    }
+    pub fn window_open(&self, now: Instant) -> bool {
+        self.state.window_open(now)
+    }
+    /// Unregisters the agent and restores the pairable state of the adapter.
+    fn close_window(&mut self) -> std::io::Result<()> {
+        self.state = AgentState::default();
+        if !self.registered {
+            return Ok(());
+        }
+        self.registered = false;
+        let was_pairable = self.was_pairable;
+        self.set_bool(ADAPTER_PATH, ADAPTER_IF, "Pairable", was_pairable)?;
+        self.agent_call("UnregisterAgent")
+    }
+    /// Trusts the devices accepted during the window once they have paired.
+    fn check_paired(&mut self) -> Vec<AgentEvent> {
+        let mut events = Vec::new();
+        for path in self.state.unpaired() {
+            match self.get_bool(&path, DEVICE_IF, "Paired") {
+                Ok(true) => (),
+                Ok(false) => continue,
+                // the device was removed
+                Err(_) => {
+                    self.state.accepted.remove(&path);
+                    continue;
+                }
+            }
+            // tried again on the next check
+            if self.set_bool(&path, DEVICE_IF, "Trusted", true).is_err() {
+                continue;
+            }
+            self.state.accepted.insert(path.clone(), true);
+            events.push(AgentEvent::Paired(device_address(&path)));
+        }
+        events
+    }
+    /// Answers pending agent requests, trusts newly paired devices and closes the window
+    /// once it has expired. Call this when the connection is readable and periodically.
+    /// An error means the connection to the system bus was lost.
+    pub fn process(&mut self, now: Instant) -> std::io::Result<Vec<AgentEvent>> {
+        for mut reply in self.conn.refill_all().map_err(dbus_error)? {
+            self.conn
+                .send_message(&mut reply, Timeout::Duration(CALL_TIMEOUT))
+                .map_err(dbus_error)?;
+        }
+        let mut events = Vec::new();
+        while let Some(call) = self.conn.try_get_call() {
+            if call.dynheader.member.as_deref() == Some("Release") {
+                self.registered = false;
+            }
+            let (mut reply, ev) = self.state.answer(&call, now);
+            self.conn
+                .send_message(&mut reply, Timeout::Duration(CALL_TIMEOUT))
+                .map_err(dbus_error)?;
+            events.extend(ev);
+        }
+        if self.registered {
+            events.extend(self.check_paired());
+        }
+        if self.state.window_end.is_some_and(|end| now >= end) {
+            self.close_window()?;
+        }
+        Ok(events)
+    }
+}
+
+impl AsRawFd for PairingAgent {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.conn().as_raw_fd()
+    }
+}
+
+impl Drop for PairingAgent {
+    fn drop(&mut self) {
+        self.close_window().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: &str = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF";
+
+    fn request(member: &str, device: &str, passkey: Option<u32>) -> MarshalledMessage {
+        let mut call = MessageBuilder::new()
+            .call(member.to_owned())
+            .on(AGENT_PATH.to_owned())
+            .with_interface("org.bluez.Agent1".to_owned())
+            .build();
+        call.body
+            .push_param(ObjectPath::new(device).unwrap())
+            .unwrap();
+        if let Some(passkey) = passkey {
+            call.body.push_param(passkey).unwrap();
+        }
+        call
+    }
+
+    fn open_window(now: Instant) -> AgentState {
+        AgentState {
+            window_end: Some(now + Duration::from_secs(60)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn confirmation_answered() {
+        let now = Instant::now();
+        let mut state = AgentState::default();
+        // outside of a pairing window every request is refused
+        let (reply, ev) = state.answer(&request("RequestConfirmation", DEVICE, Some(12345)), now);
+        assert!(matches!(reply.typ, MessageType::Error));
+        assert_eq!(reply.dynheader.error_name.as_deref(), Some(REJECTED));
+        assert_eq!(
+            ev,
+            Some(AgentEvent::Confirm {
+                passkey: "012345".into(),
+                accepted: false
+            })
+        );
+        assert!(state.accepted.is_empty());
+
+        state.window_end = Some(now + Duration::from_secs(60));
+        let (reply, ev) = state.answer(&request("RequestConfirmation", DEVICE, Some(12345)), now);
+        assert!(matches!(reply.typ, MessageType::Reply));
+        assert_eq!(
+            ev,
+            Some(AgentEvent::Confirm {
+                passkey: "012345".into(),
+                accepted: true
+            })
+        );
+        assert_eq!(state.unpaired(), [DEVICE]);
+    }
+
+    #[test]
+    fn services_of_other_devices_refused() {
+        let now = Instant::now();
+        let mut state = open_window(now);
+        let other = "/org/bluez/hci0/dev_11_22_33_44_55_66";
+        let (reply, _) = state.answer(&request("AuthorizeService", other, None), now);
+        assert!(matches!(reply.typ, MessageType::Error));
+        state.answer(&request("RequestAuthorization", DEVICE, None), now);
+        let (reply, _) = state.answer(&request("AuthorizeService", DEVICE, None), now);
+        assert!(matches!(reply.typ, MessageType::Reply));
+        // pin codes aren't supported
+        let (reply, _) = state.answer(&request("RequestPinCode", DEVICE, None), now);
+        assert!(matches!(reply.typ, MessageType::Error));
+    }
+
+    #[test]
+    fn passkey_reported_once() {
+        let now = Instant::now();
+        let mut state = open_window(now);
+        let (_, ev) = state.answer(&request("DisplayPasskey", DEVICE, Some(4711)), now);
+        assert_eq!(ev, Some(AgentEvent::Passkey("004711".into())));
+        let (reply, ev) = state.answer(&request("DisplayPasskey", DEVICE, Some(4711)), now);
+        assert!(matches!(reply.typ, MessageType::Reply));
+        assert_eq!(ev, None);
+        assert_eq!(device_address(DEVICE), "AA:BB:CC:DD:EE:FF");
+    }
+}
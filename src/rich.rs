@@ -0,0 +1,746 @@
+//! Conversion of HTML and RTF clips to plain text, and sanitising of HTML.
+
+/// Elements whose content is not shown as text.
+const HIDDEN: &[&str] = &["head", "noscript", "script", "style", "template", "title"];
+/// Elements whose content is kept as is by the tokenizer.
+const RAW_TEXT: &[&str] = &["script", "style"];
+/// Elements that end the current line.
+const BLOCKS: &[&str] = &[
+    "address", "article", "aside", "dd", "div", "dl", "dt", "footer", "form", "header", "hr", "li",
+    "nav", "ol", "section", "table", "tr", "ul",
+];
+/// Elements separated from the surrounding text by an empty line.
+const PARAGRAPHS: &[&str] = &["blockquote", "h1", "h2", "h3", "h4", "h5", "h6", "p", "pre"];
+/// Elements kept when sanitising. Other tags are dropped, keeping their content.
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "b",
+    "bdi",
+    "bdo",
+    "blockquote",
+    "br",
+    "caption",
+    "center",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "del",
+    "details",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "font",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "samp",
+    "small",
+    "span",
+    "strike",
+    "strong",
+    "sub",
+    "summary",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "time",
+    "tr",
+    "u",
+    "ul",
+    "var",
+];
+/// Attributes kept when sanitising, all others are dropped.
+const ALLOWED_ATTRS: &[&str] = &[
+    "align", "alt", "color", "colspan", "datetime", "dir", "face", "height", "href", "lang",
+    "rowspan", "size", "span", "src", "start", "title", "valign", "width",
+];
+/// Allowed attributes holding a URL.
+const URL_ATTRS: &[&str] = &["href", "src"];
+/// Elements removed along with their content when sanitising. This includes all
+/// elements whose content a browser doesn't parse as markup.
+const UNSAFE: &[&str] = &[
+    "applet",
+    "frameset",
+    "iframe",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "plaintext",
+    "script",
+    "style",
+    "template",
+    "textarea",
+    "title",
+    "xmp",
+];
+/// Query parameters used to track clicks.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "igshid", "mc_cid", "mc_eid", "msclkid", "yclid",
+];
+
+struct Tag<'a> {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attrs: Vec<(String, Option<&'a str>)>,
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    /// The content of a script or style element.
+    Raw(&'a str),
+    Tag(Tag<'a>),
+    /// Comments, doctypes and processing instructions.
+    Other,
+}
+
+fn find_from(s: &str, from: usize, pat: &str) -> Option<usize> {
+    s[from..].find(pat).map(|i| from + i)
+}
+
+/// Splits `html` into tokens. Malformed markup is treated as text.
+fn tokens(html: &str) -> Vec<Token<'_>> {
+    let bytes = html.as_bytes();
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    let mut text_start = 0;
+    while let Some(lt) = find_from(html, pos, "<") {
+        let next = bytes.get(lt + 1).copied().unwrap_or(b' ');
+        let end = if html[lt..].starts_with("<!--") {
+            find_from(html, lt + 4, "-->").map(|e| (e + 3, None))
+        } else if next == b'!' || next == b'?' {
+            find_from(html, lt, ">").map(|e| (e + 1, None))
+        } else if next == b'/' || next.is_ascii_alphabetic() {
+            parse_tag(html, lt).map(|(e, tag)| (e, Some(tag)))
+        } else {
+            None
+        };
+        let (end, tag) = match end {
+            Some(e) => e,
+            None => {
+                pos = lt + 1;
+                continue;
+            }
+        };
+        if text_start < lt {
+            ret.push(Token::Text(&html[text_start..lt]));
+        }
+        pos = end;
+        match tag {
+            Some(tag) => {
+                let raw = !tag.closing && !tag.self_closing && RAW_TEXT.contains(&&*tag.name);
+                let close = format!("</{}", tag.name);
+                ret.push(Token::Tag(tag));
+                if raw {
+                    let lower = html[end..].to_ascii_lowercase();
+                    let raw_end = lower.find(&close).map_or(html.len(), |i| end + i);
+                    ret.push(Token::Raw(&html[end..raw_end]));
+                    pos = raw_end;
+                }
+            }
+            None => ret.push(Token::Other),
+        }
+        text_start = pos;
+    }
+    if text_start < html.len() {
+        ret.push(Token::Text(&html[text_start..]));
+    }
+    ret
+}
+
+/// Parses the tag starting at `start`, returning the position after it.
+fn parse_tag(html: &str, start: usize) -> Option<(usize, Tag<'_>)> {
+    let bytes = html.as_bytes();
+    let mut i = start + 1;
+    let closing = bytes[i] == b'/';
+    if closing {
+        i += 1;
+    }
+    let word_end = |mut i: usize| {
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"/>=".contains(&bytes[i]) {
+            i += 1;
+        }
+        i
+    };
+    let name_end = word_end(i);
+    if name_end == i {
+        return None;
+    }
+    let mut tag = Tag {
+        name: html[i..name_end].to_ascii_lowercase(),
+        closing,
+        self_closing: false,
+        attrs: Vec::new(),
+    };
+    i = name_end;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        match bytes.get(i).copied()? {
+            b'>' => return Some((i + 1, tag)),
+            b'/' => {
+                tag.self_closing = bytes.get(i + 1) == Some(&b'>');
+                i += 1;
+                continue;
+            }
+            _ => (),
+        }
+        let attr_end = word_end(i);
+        if attr_end == i {
+            // a stray '='
+            i += 1;
+            continue;
+        }
+        let name = html[i..attr_end].to_ascii_lowercase();
+        i = attr_end;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if bytes.get(i) != Some(&b'=') {
+            tag.attrs.push((name, None));
+            continue;
+        }
+        i += 1;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let value = match bytes.get(i).copied()? {
+            q @ b'"' | q @ b'\'' => {
+                let end = find_from(html, i + 1, if q == b'"' { "\"" } else { "'" })?;
+                let v = &html[i + 1..end];
+                i = end + 1;
+                v
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                    i += 1;
+                }
+                &html[start..i]
+            }
+        };
+        tag.attrs.push((name, Some(value)));
+    }
+}
+
+/// Replaces character references with the characters they stand for.
+pub fn decode_entities(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        ret.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let semi = rest
+            .char_indices()
+            .take(12)
+            .find(|(_, c)| *c == ';')
+            .map(|(i, _)| i);
+        let decoded = semi.and_then(|semi| {
+            let name = &rest[1..semi];
+            let c = match name {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => '\u{a0}',
+                "hellip" => '…',
+                "mdash" => '—',
+                "ndash" => '–',
+                "lsquo" => '‘',
+                "rsquo" => '’',
+                "ldquo" => '“',
+                "rdquo" => '”',
+                "copy" => '©',
+                "reg" => '®',
+                _ => {
+                    let code = if let Some(hex) =
+                        name.strip_prefix("#x").or_else(|| name.strip_prefix("#X"))
+                    {
+                        u32::from_str_radix(hex, 16).ok()?
+                    } else {
+                        name.strip_prefix('#')?.parse().ok()?
+                    };
+                    std::char::from_u32(code)?
+                }
+            };
+            Some((c, semi + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                ret.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Ends the current line, and leaves an empty line if `blank`.
+fn new_line(out: &mut String, blank: bool) {
+    let len = out.trim_end_matches(' ').len();
+    out.truncate(len);
+    if out.is_empty() {
+        return;
+    }
+    let want = if blank { "\n\n" } else { "\n" };
+    while !out.ends_with(want) {
+        out.push('\n');
+    }
+}
+
+/// Separates words, unless at the start of a line.
+fn space(out: &mut String) {
+    if !out.is_empty() && !out.ends_with([' ', '\n', '\t']) {
+        out.push(' ');
+    }
+}
+
+/// Converts HTML to the text a browser would show, with list items on their own lines.
+pub fn html_to_text(html: &str) -> String {
+    let mut out = String::new();
+    let mut hidden = 0_usize;
+    let mut pre = 0_usize;
+    for token in tokens(html) {
+        let tag = match token {
+            Token::Text(t) if hidden == 0 => {
+                let t = decode_entities(t).replace('\u{a0}', " ");
+                if pre > 0 {
+                    out.push_str(&t);
+                    continue;
+                }
+                // runs of whitespace collapse to a single space
+                let ws = |c: char| c.is_ascii_whitespace();
+                if t.starts_with(ws) {
+                    space(&mut out);
+                }
+                let mut words = t.split_ascii_whitespace().peekable();
+                while let Some(word) = words.next() {
+                    out.push_str(word);
+                    if words.peek().is_some() || t.ends_with(ws) {
+                        space(&mut out);
+                    }
+                }
+                continue;
+            }
+            Token::Tag(tag) => tag,
+            _ => continue,
+        };
+        let name = &*tag.name;
+        if HIDDEN.contains(&name) {
+            if tag.closing {
+                hidden = hidden.saturating_sub(1);
+            } else if !tag.self_closing {
+                hidden += 1;
+            }
+            continue;
+        }
+        if hidden > 0 {
+            continue;
+        }
+        if name == "pre" {
+            pre = if tag.closing {
+                pre.saturating_sub(1)
+            } else {
+                pre + 1
+            };
+        }
+        match name {
+            "br" => {
+                let len = out.trim_end_matches(' ').len();
+                out.truncate(len);
+                out.push('\n');
+            }
+            "li" if !tag.closing => {
+                new_line(&mut out, false);
+                out.push_str("- ");
+            }
+            "td" | "th" if !tag.closing && !out.is_empty() && !out.ends_with('\n') => {
+                out.push('\t');
+            }
+            _ if PARAGRAPHS.contains(&name) => new_line(&mut out, true),
+            _ if BLOCKS.contains(&name) => new_line(&mut out, false),
+            _ => (),
+        }
+    }
+    let lines: Vec<&str> = out.lines().map(|l| l.trim_end()).collect();
+    lines.join("\n").trim_matches('\n').to_owned()
+}
+
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Removes the query parameters used to track clicks from `url`.
+fn strip_tracking(url: &str) -> String {
+    let (rest, fragment) = match url.find('#') {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let (base, query) = match rest.find('?') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => return url.to_owned(),
+    };
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|p| {
+            let key = p.split('=').next().unwrap_or("");
+            !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key)
+        })
+        .collect();
+    if params.is_empty() {
+        format!("{}{}", base, fragment)
+    } else {
+        format!("{}?{}{}", base, params.join("&"), fragment)
+    }
+}
+
+/// Returns `true` for URLs that run code or embed a document when followed.
+/// Only data URLs of raster images are allowed.
+fn is_unsafe_url(url: &str) -> bool {
+    // browsers ignore whitespace and control characters in the scheme
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if let Some(data) = url.strip_prefix("data:") {
+        return !data.starts_with("image/") || data.starts_with("image/svg");
+    }
+    url.starts_with("javascript:") || url.starts_with("vbscript:")
+}
+
+/// Returns `true` for images that are too small to see, used to track when a page is read.
+fn is_tracking_pixel(tag: &Tag) -> bool {
+    let dim = |name: &str| {
+        tag.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.map(|v| v.trim_end_matches("px").trim()))
+    };
+    let tiny = |v: Option<&str>| matches!(v, Some("0") | Some("1"));
+    tag.name == "img" && tiny(dim("width")) && tiny(dim("height"))
+}
+
+fn write_tag(out: &mut String, tag: &Tag) {
+    out.push('<');
+    if tag.closing {
+        out.push('/');
+    }
+    out.push_str(&tag.name);
+    for (name, value) in &tag.attrs {
+        if !ALLOWED_ATTRS.contains(&&**name) {
+            continue;
+        }
+        let value = match value.map(decode_entities) {
+            Some(v) if URL_ATTRS.contains(&&**name) => {
+                if is_unsafe_url(&v) {
+                    continue;
+                }
+                Some(strip_tracking(&v))
+            }
+            v => v,
+        };
+        out.push(' ');
+        out.push_str(name);
+        if let Some(v) = value {
+            out.push_str("=\"");
+            out.push_str(&escape_attr(&v));
+            out.push('"');
+        }
+    }
+    if tag.self_closing {
+        out.push_str(" /");
+    }
+    out.push('>');
+}
+
+/// Keeps only the allowed elements and attributes of HTML. Scripts, styles and
+/// embedded frames are removed with their content, as are script and data URLs,
+/// tracking pixels and tracking parameters of links. Any text is escaped.
+pub fn sanitize_html(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut removed = 0_usize;
+    for token in tokens(html) {
+        match token {
+            Token::Tag(tag) if UNSAFE.contains(&&*tag.name) => {
+                if tag.closing {
+                    removed = removed.saturating_sub(1);
+                } else if !tag.self_closing {
+                    removed += 1;
+                }
+            }
+            _ if removed > 0 => (),
+            Token::Tag(tag) => {
+                if ALLOWED_TAGS.contains(&&*tag.name) && !is_tracking_pixel(&tag) {
+                    write_tag(&mut out, &tag);
+                }
+            }
+            // comments, doctypes and processing instructions
+            Token::Other => (),
+            Token::Text(t) | Token::Raw(t) => out.push_str(&t.replace('<', "&lt;")),
+        }
+    }
+    out
+}
+
+/// Destinations of RTF groups that don't contain document text.
+const RTF_SKIPPED: &[&str] = &[
+    "colortbl",
+    "fonttbl",
+    "footer",
+    "header",
+    "info",
+    "pict",
+    "stylesheet",
+];
+
+/// Extracts the text of an RTF document.
+pub fn rtf_to_text(rtf: &str) -> String {
+    let chars: Vec<char> = rtf.chars().collect();
+    let mut out = String::new();
+    let mut depth = 0_usize;
+    // the depth of the group being skipped
+    let mut skip: Option<usize> = None;
+    // the number of characters standing in for a \u character, and the ones still to skip
+    let mut uc = 1_usize;
+    let mut fallback = 0_usize;
+    let mut i = 0;
+    let push = |out: &mut String, fallback: &mut usize, c: char| {
+        if *fallback > 0 {
+            *fallback -= 1;
+        } else {
+            out.push(c);
+        }
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                if skip == Some(depth) {
+                    skip = None;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            '\r' | '\n' => (),
+            '\\' => {
+                let next = match chars.get(i) {
+                    Some(&n) => n,
+                    None => break,
+                };
+                if next.is_ascii_alphabetic() {
+                    let start = i;
+                    while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                        i += 1;
+                    }
+                    let word: String = chars[start..i].iter().collect();
+                    let param_start = i;
+                    if chars.get(i) == Some(&'-') {
+                        i += 1;
+                    }
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    let param: Option<i32> = chars[param_start..i]
+                        .iter()
+                        .collect::<String>()
+                        .parse()
+                        .ok();
+                    if chars.get(i) == Some(&' ') {
+                        i += 1;
+                    }
+                    if skip.is_some() {
+                        continue;
+                    }
+                    match word.as_str() {
+                        "par" | "line" | "row" => out.push('\n'),
+                        "tab" | "cell" => out.push('\t'),
+                        "uc" => uc = param.unwrap_or(1).max(0) as usize,
+                        "u" => {
+                            if let Some(n) = param {
+                                // values above 32767 are written as negative numbers
+                                let n = if n < 0 { n + 65536 } else { n };
+                                if let Some(c) = std::char::from_u32(n as u32) {
+                                    out.push(c);
+                                }
+                                fallback = uc;
+                            }
+                        }
+                        w if RTF_SKIPPED.contains(&w) => skip = Some(depth),
+                        _ => (),
+                    }
+                } else if next == '\'' {
+                    let hex: String = chars.iter().skip(i + 1).take(2).collect();
+                    i += 3;
+                    if skip.is_none() {
+                        if let Ok(b) = u8::from_str_radix(&hex, 16) {
+                            // close enough to the Windows code pages used by RTF
+                            push(&mut out, &mut fallback, b as char);
+                        }
+                    }
+                } else {
+                    i += 1;
+                    match next {
+                        '*' if skip.is_none() => {
+                            skip = Some(depth);
+                        }
+                        _ if skip.is_some() => (),
+                        '{' | '}' | '\\' => push(&mut out, &mut fallback, next),
+                        '~' => push(&mut out, &mut fallback, ' '),
+                        '\r' | '\n' => out.push('\n'),
+                        _ => (),
+                    }
+                }
+            }
+            _ => {
+                if skip.is_none() {
+                    push(&mut out, &mut fallback, c);
+                }
+            }
+        }
+    }
+    out.trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_urls_removed() {
+        let html = r#"<a href="javascript:alert(1)">a</a><a HREF=" JaVa&#x09;Script:alert(1)">b</a><a href="&#106;avascript:alert(1)">c</a><img src="vbscript:msgbox(1)">"#;
+        assert_eq!(sanitize_html(html), "<a>a</a><a>b</a><a>c</a><img>");
+    }
+
+    #[test]
+    fn unknown_markup_dropped() {
+        let html = r#"<svg><a xlink:href="javascript:alert(1)" xlink:onclick="x()"><text>t</text></a></svg>"#;
+        assert_eq!(sanitize_html(html), "<a>t</a>");
+        let html = r#"<svg><a><animate attributeName="href" values="https://example.com;javascript:alert(1)" /></a></svg>"#;
+        assert_eq!(sanitize_html(html), "<a></a>");
+        let html =
+            r#"<!doctype html><form action="/x"><input value="v"><b data-x="1">b</b></form>"#;
+        assert_eq!(sanitize_html(html), "<b>b</b>");
+    }
+
+    #[test]
+    fn data_urls_removed() {
+        let html = r#"<a href="data:text/html;base64,PHNjcmlwdD4=">a</a><object data="data:text/html,x"></object><video poster="data:image/svg+xml,x"></video>"#;
+        assert_eq!(sanitize_html(html), "<a>a</a>");
+        let html = r#"<img src="data:image/png;base64,iVBORw0KGgo=" srcset="a.png 1x, data:text/html,x 2x">"#;
+        assert_eq!(
+            sanitize_html(html),
+            r#"<img src="data:image/png;base64,iVBORw0KGgo=">"#
+        );
+        let html = r#"<img src="data:image/svg+xml,x" background="javascript:x">"#;
+        assert_eq!(sanitize_html(html), "<img>");
+    }
+
+    #[test]
+    fn raw_text_escaped() {
+        // the content of these elements isn't markup to a browser, but it is to the tokenizer
+        for html in &[
+            "<textarea><style></textarea><img src=x onerror=alert(1)>",
+            "<noscript><style></noscript><img src=x onerror=alert(1)>",
+            "<svg><style><img src=x onerror=alert(1)></style></svg>",
+            "<title><script></title><img src=x onerror=alert(1)>",
+        ] {
+            assert_eq!(sanitize_html(html), "", "{}", html);
+        }
+        let html = r#"<a title="</noscript><img src=x onerror=alert(1)>">t</a>"#;
+        assert_eq!(
+            sanitize_html(html),
+            r#"<a title="&lt;/noscript&gt;&lt;img src=x onerror=alert(1)&gt;">t</a>"#
+        );
+        let html = "<b>1 < 2</b><img src=x onerror=alert(1)";
+        assert_eq!(
+            sanitize_html(html),
+            "<b>1 &lt; 2</b>&lt;img src=x onerror=alert(1)"
+        );
+    }
+
+    #[test]
+    fn styles_removed() {
+        let html = r#"<style>p { background: url(https://t.example/p) }</style><p style="background: url(https://t.example/p)">x</p>"#;
+        assert_eq!(sanitize_html(html), "<p>x</p>");
+    }
+
+    #[test]
+    fn tracking_removed() {
+        let html = "<p onclick=\"x()\">Hi<script>alert(1)</script><!-- c --></p>\
+                    <a href=\"https://example.com/?utm_source=x&amp;id=3#top\" ping=\"/p\">l</a>\
+                    <img src=\"/t.gif\" width=\"1\" height=\"1px\"><iframe src=\"/f\">x</iframe>";
+        assert_eq!(
+            sanitize_html(html),
+            "<p>Hi</p><a href=\"https://example.com/?id=3#top\">l</a>"
+        );
+        assert_eq!(strip_tracking("https://e.com/?fbclid=1"), "https://e.com/");
+    }
+
+    #[test]
+    fn html_text() {
+        let html = "<html><head><title>T</title><style>p{}</style></head><body>\
+                    <h1>Title</h1><p>Some   <b>bold</b>\n text&nbsp;&amp; more</p>\
+                    <ul><li>one</li><li>two</li></ul>line<br>break\
+                    <table><tr><td>a</td><td>b</td></tr></table><pre>  x\n  y</pre></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Title\n\nSome bold text & more\n\n- one\n- two\nline\nbreak\na\tb\n\n  x\n  y"
+        );
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            decode_entities("&lt;a&gt; &#65;&#x42; &bogus; &"),
+            "<a> AB &bogus; &"
+        );
+    }
+
+    #[test]
+    fn rtf_text() {
+        let rtf = r"{\rtf1\ansi{\fonttbl{\f0 Arial;}}{\colortbl;\red0\green0\blue0;}
+{\*\generator Word;}\f0 Hello {\b world}\par
+caf\'e9 \u8364? \{x\}\tab y}";
+        assert_eq!(rtf_to_text(rtf), "Hello world\ncafé € {x}\ty");
+    }
+}
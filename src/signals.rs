@@ -0,0 +1,82 @@
+use nix::fcntl::OFlag;
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{pipe2, read, write};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+const HANDLED: [Signal; 3] = [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP];
+
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(sig: c_int) {
+    let fd = PIPE_WRITE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        write(fd, &[sig as u8]).ok();
+    }
+}
+
+/// Delivers SIGTERM, SIGINT and SIGHUP through a pipe so they can be polled with other fds.
+///
+/// A handler writing to a pipe is used rather than a signalfd because the latter
+/// requires blocking the signals, which would be inherited by the `wl-copy` children.
+pub struct Signals {
+    read: RawFd,
+}
+
+impl Signals {
+    pub fn install() -> nix::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        PIPE_WRITE.store(write, Ordering::Relaxed);
+        let action = SigAction::new(
+            SigHandler::Handler(on_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        for sig in HANDLED.iter() {
+            unsafe {
+                sigaction(*sig, &action)?;
+            }
+        }
+        Ok(Self { read })
+    }
+    /// Returns the signals received since the last call.
+    pub fn pending(&mut self) -> Vec<Signal> {
+        let mut ret = Vec::new();
+        let mut buf = [0; 16];
+        while let Ok(len) = read(self.read, &mut buf) {
+            if len == 0 {
+                break;
+            }
+            for b in &buf[..len] {
+                if let Some(sig) = HANDLED.iter().find(|s| **s as i32 == *b as i32) {
+                    ret.push(*sig);
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::raise;
+
+    #[test]
+    fn signals_delivered_through_pipe() {
+        let mut signals = Signals::install().unwrap();
+        assert!(signals.pending().is_empty());
+        raise(Signal::SIGHUP).unwrap();
+        raise(Signal::SIGTERM).unwrap();
+        assert_eq!(signals.pending(), [Signal::SIGHUP, Signal::SIGTERM]);
+        assert!(signals.pending().is_empty());
+    }
+}
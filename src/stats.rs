@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Number of transfers kept for the rolling summary.
+const ROLLING_LEN: usize = 32;
+
+/// Statistics collected for a single outbound transfer.
+#[derive(Clone, Debug)]
+pub struct TransferStats {
+    pub bytes: usize,
+    pub start: Instant,
+    pub duration: Duration,
+    pub sent_bytes: usize,
+    pub retransmitted_bytes: usize,
+    pub notify_len: usize,
+    pub min_notify_len: usize,
+    pub max_notify_len: usize,
+    pub dup_acks: u32,
+    pub timeouts: u32,
+    pub completed: bool,
+    pub abandoned: bool,
+}
+
+impl TransferStats {
+    pub fn new(bytes: usize, notify_len: usize, start: Instant) -> Self {
+        Self {
+            bytes,
+            start,
+            duration: Duration::default(),
+            sent_bytes: 0,
+            retransmitted_bytes: 0,
+            notify_len,
+            min_notify_len: notify_len,
+            max_notify_len: notify_len,
+            dup_acks: 0,
+            timeouts: 0,
+            completed: false,
+            abandoned: false,
+        }
+    }
+    pub fn update_notify_len(&mut self, notify_len: usize) {
+        self.notify_len = notify_len;
+        self.min_notify_len = self.min_notify_len.min(notify_len);
+        self.max_notify_len = self.max_notify_len.max(notify_len);
+    }
+    pub fn finish(&mut self, now: Instant, completed: bool) {
+        self.duration = now.saturating_duration_since(self.start);
+        self.completed = completed;
+    }
+    /// Throughput in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl std::fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes in {:.2}s ({:.0} B/s), {} bytes retransmitted, {} duplicate ACKs, {} timeouts, notify_len {} (min {}, max {}){}",
+            self.bytes,
+            self.duration.as_secs_f64(),
+            self.throughput(),
+            self.retransmitted_bytes,
+            self.dup_acks,
+            self.timeouts,
+            self.notify_len,
+            self.min_notify_len,
+            self.max_notify_len,
+            if self.completed {
+                ""
+            } else if self.abandoned {
+                ", abandoned"
+            } else {
+                ", aborted"
+            }
+        )
+    }
+}
+
+/// Running totals and a rolling window of recent transfers.
+#[derive(Default, Debug)]
+pub struct StatsSummary {
+    recent: VecDeque<TransferStats>,
+    completed: u64,
+    aborted: u64,
+    abandoned: u64,
+    timeouts: u64,
+    bytes: u64,
+    retransmitted_bytes: u64,
+    dup_acks: u64,
+    inbound_errors: BTreeMap<&'static str, u64>,
+}
+
+impl StatsSummary {
+    pub fn record(&mut self, stats: TransferStats) {
+        if stats.completed {
+            self.completed += 1;
+            self.bytes += stats.bytes as u64;
+        } else if stats.abandoned {
+            self.abandoned += 1;
+        } else {
+            self.aborted += 1;
+        }
+        self.timeouts += stats.timeouts as u64;
+        self.retransmitted_bytes += stats.retransmitted_bytes as u64;
+        self.dup_acks += stats.dup_acks as u64;
+        if self.recent.len() == ROLLING_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(stats);
+    }
+    /// Counts an inbound transfer rejected for the reason `kind`.
+    pub fn record_inbound_error(&mut self, kind: &'static str) {
+        *self.inbound_errors.entry(kind).or_insert(0) += 1;
+    }
+    pub fn last(&self) -> Option<&TransferStats> {
+        self.recent.back()
+    }
+    /// Average throughput in bytes per second of the recent completed transfers.
+    pub fn avg_throughput(&self) -> f64 {
+        let (bytes, secs) = self
+            .recent
+            .iter()
+            .filter(|s| s.completed)
+            .fold((0.0, 0.0), |(b, t), s| {
+                (b + s.bytes as f64, t + s.duration.as_secs_f64())
+            });
+        if secs > 0.0 {
+            bytes / secs
+        } else {
+            0.0
+        }
+    }
+    /// Ratio of retransmitted bytes to sent bytes over the recent transfers.
+    pub fn retransmit_ratio(&self) -> f64 {
+        let (retrans, sent) = self.recent.iter().fold((0.0, 0.0), |(r, s), t| {
+            (r + t.retransmitted_bytes as f64, s + t.sent_bytes as f64)
+        });
+        if sent > 0.0 {
+            retrans / sent
+        } else {
+            0.0
+        }
+    }
+    /// Renders the summary in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, val: f64| {
+            writeln!(out, "# HELP airboard_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE airboard_{} {}", name, kind).unwrap();
+            writeln!(out, "airboard_{} {}", name, val).unwrap();
+        };
+        metric(
+            "transfers_completed_total",
+            "counter",
+            "Outbound transfers completed.",
+            self.completed as f64,
+        );
+        metric(
+            "transfers_aborted_total",
+            "counter",
+            "Outbound transfers replaced before completion.",
+            self.aborted as f64,
+        );
+        metric(
+            "transfers_abandoned_total",
+            "counter",
+            "Outbound transfers abandoned after exhausting their retries.",
+            self.abandoned as f64,
+        );
+        metric(
+            "retransmission_timeouts_total",
+            "counter",
+            "Retransmission timeouts of outbound transfers.",
+            self.timeouts as f64,
+        );
+        metric(
+            "transfer_bytes_total",
+            "counter",
+            "Bytes of completed outbound transfers.",
+            self.bytes as f64,
+        );
+        metric(
+            "retransmitted_bytes_total",
+            "counter",
+            "Bytes notified more than once.",
+            self.retransmitted_bytes as f64,
+        );
+        metric(
+            "duplicate_acks_total",
+            "counter",
+            "Duplicate ACKs received from clients.",
+            self.dup_acks as f64,
+        );
+        metric(
+            "throughput_bytes_per_second",
+            "gauge",
+            "Average throughput of recent completed transfers.",
+            self.avg_throughput(),
+        );
+        metric(
+            "retransmit_ratio",
+            "gauge",
+            "Retransmitted bytes over sent bytes of recent transfers.",
+            self.retransmit_ratio(),
+        );
+        if let Some(last) = self.last() {
+            metric(
+                "last_transfer_bytes",
+                "gauge",
+                "Size of the last transfer.",
+                last.bytes as f64,
+            );
+            metric(
+                "last_transfer_seconds",
+                "gauge",
+                "Duration of the last transfer.",
+                last.duration.as_secs_f64(),
+            );
+            metric(
+                "last_notify_len",
+                "gauge",
+                "Final notify_len of the last transfer.",
+                last.notify_len as f64,
+            );
+            metric(
+                "last_min_notify_len",
+                "gauge",
+                "Minimum notify_len of the last transfer.",
+                last.min_notify_len as f64,
+            );
+            metric(
+                "last_max_notify_len",
+                "gauge",
+                "Maximum notify_len of the last transfer.",
+                last.max_notify_len as f64,
+            );
+        }
+        if !self.inbound_errors.is_empty() {
+            writeln!(
+                out,
+                "# HELP airboard_inbound_errors_total Inbound transfers rejected by reason."
+            )
+            .unwrap();
+            writeln!(out, "# TYPE airboard_inbound_errors_total counter").unwrap();
+            for (kind, count) in &self.inbound_errors {
+                writeln!(
+                    out,
+                    "airboard_inbound_errors_total{{kind=\"{}\"}} {}",
+                    kind, count
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl std::fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "transfers: {} completed, {} aborted, {} abandoned",
+            self.completed, self.aborted, self.abandoned
+        )?;
+        writeln!(
+            f,
+            "bytes: {} sent, {} retransmitted, {} duplicate ACKs, {} timeouts",
+            self.bytes, self.retransmitted_bytes, self.dup_acks, self.timeouts
+        )?;
+        writeln!(
+            f,
+            "recent: {:.0} B/s average, {:.1}% retransmitted",
+            self.avg_throughput(),
+            self.retransmit_ratio() * 100.0
+        )?;
+        for (kind, count) in &self.inbound_errors {
+            writeln!(f, "inbound errors: {} {}", count, kind)?;
+        }
+        if let Some(last) = self.last() {
+            writeln!(f, "last: {}", last)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(bytes: usize, secs: u64, completed: bool) -> TransferStats {
+        let start = Instant::now();
+        let mut stats = TransferStats::new(bytes, 64, start);
+        stats.sent_bytes = bytes + bytes / 4;
+        stats.retransmitted_bytes = bytes / 4;
+        stats.finish(start + Duration::from_secs(secs), completed);
+        stats
+    }
+
+    #[test]
+    fn notify_len_range() {
+        let mut stats = TransferStats::new(100, 123, Instant::now());
+        stats.update_notify_len(64);
+        stats.update_notify_len(244);
+        stats.update_notify_len(169);
+        assert_eq!(stats.notify_len, 169);
+        assert_eq!(stats.min_notify_len, 64);
+        assert_eq!(stats.max_notify_len, 244);
+    }
+
+    #[test]
+    fn summary_counts() {
+        let mut summary = StatsSummary::default();
+        summary.record(transfer(1000, 1, true));
+        summary.record(transfer(3000, 1, true));
+        summary.record(transfer(500, 1, false));
+        let mut abandoned = transfer(200, 1, false);
+        abandoned.abandoned = true;
+        summary.record(abandoned);
+        assert_eq!(summary.completed, 2);
+        assert_eq!(summary.aborted, 1);
+        assert_eq!(summary.abandoned, 1);
+        assert_eq!(summary.bytes, 4000);
+        // only completed transfers count towards the throughput
+        assert_eq!(summary.avg_throughput(), 2000.0);
+        assert_eq!(summary.retransmit_ratio(), 0.2);
+        assert_eq!(summary.last().unwrap().bytes, 200);
+    }
+
+    #[test]
+    fn rolling_window() {
+        let mut summary = StatsSummary::default();
+        for i in 0..ROLLING_LEN + 5 {
+            summary.record(transfer(i + 1, 1, true));
+        }
+        assert_eq!(summary.recent.len(), ROLLING_LEN);
+        assert_eq!(summary.recent.front().unwrap().bytes, 6);
+        assert_eq!(summary.completed, ROLLING_LEN as u64 + 5);
+    }
+
+    #[test]
+    fn prometheus() {
+        let mut summary = StatsSummary::default();
+        let out = summary.to_prometheus();
+        assert!(out.contains("airboard_transfers_completed_total 0\n"));
+        assert!(!out.contains("airboard_last_transfer_bytes"));
+        assert!(!out.contains("airboard_inbound_errors_total"));
+
+        summary.record(transfer(1000, 2, true));
+        summary.record_inbound_error("oversize");
+        summary.record_inbound_error("oversize");
+        let out = summary.to_prometheus();
+        assert!(out.contains("# TYPE airboard_transfers_completed_total counter\n"));
+        assert!(out.contains("airboard_transfers_completed_total 1\n"));
+        assert!(out.contains("airboard_throughput_bytes_per_second 500\n"));
+        assert!(out.contains("airboard_last_transfer_bytes 1000\n"));
+        assert!(out.contains("airboard_inbound_errors_total{kind=\"oversize\"} 2\n"));
+    }
+}
@@ -0,0 +1,212 @@
+//! Decoding of text clips in the charsets offered by the local clipboard.
+
+use crate::config::TextConfig;
+use crate::Clip;
+
+/// The mime type text clips are converted to.
+pub const UTF8_MIME: &str = "text/plain;charset=utf-8";
+
+/// Returns the lowercase charset parameter of `mime`, if any.
+pub fn charset(mime: &str) -> Option<String> {
+    mime.split(';').skip(1).find_map(|param| {
+        let mut kv = param.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("charset") => {
+                Some(v.trim().trim_matches('"').to_ascii_lowercase())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Returns `true` if `mime` is plain text, in any charset, including the X11 text targets.
+pub fn is_text(mime: &str) -> bool {
+    let base = mime.split(';').next().unwrap_or("").trim();
+    base.eq_ignore_ascii_case("text/plain") || matches!(base, "UTF8_STRING" | "STRING" | "TEXT")
+}
+
+/// Returns the charset of a text target.
+fn target_charset(mime: &str) -> Option<String> {
+    match mime {
+        "UTF8_STRING" | "TEXT" => Some("utf-8".to_owned()),
+        "STRING" => Some("iso-8859-1".to_owned()),
+        _ => charset(mime),
+    }
+}
+
+/// Ranks the text targets offered by the clipboard, lower is better.
+/// Returns `None` for targets that aren't text or whose charset can't be decoded.
+pub fn target_rank(mime: &str) -> Option<u8> {
+    if !is_text(mime) {
+        return None;
+    }
+    let rank = match mime {
+        UTF8_MIME => 0,
+        "UTF8_STRING" => 1,
+        // often mislabeled, so they are used last
+        "STRING" | "TEXT" => 5,
+        _ => match charset(mime).as_deref() {
+            Some("utf-8") | Some("utf8") => 2,
+            Some(cs) if decode(b"", Some(cs)).is_some() => 3,
+            Some(_) => return None,
+            None => 4,
+        },
+    };
+    Some(rank)
+}
+
+/// Decodes `data` in `charset`, which defaults to UTF-8.
+/// Returns `None` if the charset is unknown or the data isn't valid in it.
+pub fn decode(data: &[u8], charset: Option<&str>) -> Option<String> {
+    match charset.unwrap_or("utf-8") {
+        "utf-8" | "utf8" => String::from_utf8(data.to_vec()).ok(),
+        "us-ascii" | "ascii" => {
+            if data.is_ascii() {
+                String::from_utf8(data.to_vec()).ok()
+            } else {
+                None
+            }
+        }
+        // every byte maps to the code point of the same value
+        "iso-8859-1" | "latin1" => Some(data.iter().map(|&b| b as char).collect()),
+        "utf-16" => match data {
+            [0xfe, 0xff, rest @ ..] => decode_utf16(rest, true),
+            [0xff, 0xfe, rest @ ..] => decode_utf16(rest, false),
+            _ => decode_utf16(data, true),
+        },
+        "utf-16be" => decode_utf16(data, true),
+        "utf-16le" => decode_utf16(data, false),
+        _ => None,
+    }
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> Option<String> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = data.chunks(2).map(|c| {
+        if big_endian {
+            u16::from_be_bytes([c[0], c[1]])
+        } else {
+            u16::from_le_bytes([c[0], c[1]])
+        }
+    });
+    std::char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+}
+
+/// Decodes a text clip, removing a byte order mark.
+fn decode_clip(data: &[u8], mime: &str) -> Option<String> {
+    if !is_text(mime) {
+        return None;
+    }
+    let text = decode(data, target_charset(mime).as_deref())?;
+    match text.strip_prefix('\u{feff}') {
+        Some(stripped) => Some(stripped.to_owned()),
+        None => Some(text),
+    }
+}
+
+fn normalise_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+fn strip_trailing_whitespace(text: &str) -> String {
+    let mut ret: Vec<&str> = text.split('\n').map(|l| l.trim_end()).collect();
+    while ret.len() > 1 && ret.last() == Some(&"") {
+        ret.pop();
+    }
+    ret.join("\n")
+}
+
+/// Decodes a text clip and normalises it, so that the same text in
+/// different encodings compares equal.
+pub fn comparable(data: &[u8], mime: &str) -> Option<String> {
+    let text = decode_clip(data, mime)?;
+    Some(strip_trailing_whitespace(&normalise_line_endings(&text)))
+}
+
+/// Converts a text clip to UTF-8 with the normalisations in `config`.
+/// Returns `None` if `clip` isn't text or can't be decoded.
+pub fn to_utf8(clip: &Clip, config: &TextConfig) -> Option<Clip> {
+    let mut text = decode_clip(clip.data(), clip.mime())?;
+    if config.normalise_line_endings {
+        text = normalise_line_endings(&text);
+    }
+    if config.strip_trailing_whitespace {
+        text = strip_trailing_whitespace(&text);
+    }
+    Some(Clip::new(text.into_bytes(), UTF8_MIME.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charsets() {
+        assert_eq!(
+            charset("text/plain; charset=\"UTF-16LE\""),
+            Some("utf-16le".into())
+        );
+        assert_eq!(charset("text/plain;format=flowed"), None);
+        assert!(is_text("TEXT/PLAIN;charset=utf-8"));
+        assert!(is_text("UTF8_STRING"));
+        assert!(!is_text("text/html"));
+    }
+
+    #[test]
+    fn targets_ranked() {
+        assert_eq!(target_rank(UTF8_MIME), Some(0));
+        assert_eq!(target_rank("UTF8_STRING"), Some(1));
+        assert_eq!(target_rank("text/plain;charset=utf-16"), Some(3));
+        assert_eq!(target_rank("text/plain"), Some(4));
+        assert_eq!(target_rank("STRING"), Some(5));
+        assert_eq!(target_rank("text/plain;charset=koi8-r"), None);
+        assert_eq!(target_rank("image/png"), None);
+    }
+
+    #[test]
+    fn decoded() {
+        assert_eq!(decode(b"caf\xc3\xa9", None).unwrap(), "café");
+        assert_eq!(decode(b"caf\xe9", None), None);
+        assert_eq!(decode(b"caf\xe9", Some("latin1")).unwrap(), "café");
+        assert_eq!(decode(b"caf\xe9", Some("ascii")), None);
+        assert_eq!(decode(b"\xfe\xff\0h\0i", Some("utf-16")).unwrap(), "hi");
+        assert_eq!(decode(b"\xff\xfeh\0i\0", Some("utf-16")).unwrap(), "hi");
+        assert_eq!(decode(b"h\0i\0", Some("utf-16le")).unwrap(), "hi");
+        // odd lengths and unpaired surrogates are invalid
+        assert_eq!(decode(b"h\0i", Some("utf-16le")), None);
+        assert_eq!(decode(b"\x00\xd8", Some("utf-16le")), None);
+        assert_eq!(decode(b"hi", Some("ebcdic")), None);
+    }
+
+    #[test]
+    fn converted_to_utf8() {
+        let clip = Clip::new(
+            b"\xef\xbb\xbfline  \r\nnext\rlast\n\n".to_vec(),
+            "text/plain".to_owned(),
+        );
+        let plain = to_utf8(&clip, &TextConfig::default()).unwrap();
+        assert_eq!(plain.mime(), UTF8_MIME);
+        assert_eq!(plain.data(), b"line  \r\nnext\rlast\n\n");
+        let config = TextConfig {
+            normalise_line_endings: true,
+            strip_trailing_whitespace: true,
+        };
+        let normal = to_utf8(&clip, &config).unwrap();
+        assert_eq!(normal.data(), b"line\nnext\nlast");
+        let html = Clip::new(b"<b>hi</b>".to_vec(), "text/html".to_owned());
+        assert!(to_utf8(&html, &config).is_none());
+    }
+
+    #[test]
+    fn comparable_text() {
+        assert_eq!(
+            comparable(b"a \r\nb\r\n", "text/plain"),
+            comparable(b"\xff\xfea\0\n\0b\0", "text/plain;charset=utf-16")
+        );
+        assert_eq!(comparable(b"a", "image/png"), None);
+    }
+}
@@ -0,0 +1,206 @@
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+
+/// Streaming SHA-256 verification of an inbound transfer.
+///
+/// The hash of the whole clip is updated as data arrives, so it doesn't need to be
+/// computed once the transfer is complete. If the client sends the hashes of fixed size
+/// blocks, each block is also checked as soon as it is complete so that a corrupted block
+/// can be requested again instead of the whole clip.
+pub(crate) struct Verifier {
+    hasher: Sha256,
+    block_start: Sha256,
+    block: Sha256,
+    block_size: u32,
+    block_hashes: HashMap<u32, [u8; 32]>,
+    fed: u32,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            block_start: Sha256::new(),
+            block: Sha256::new(),
+            block_size: 0,
+            block_hashes: HashMap::new(),
+            fed: 0,
+        }
+    }
+}
+
+impl Verifier {
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+    /// Adds the hashes of the blocks starting at `first` of a `msg_length` byte transfer.
+    /// Returns `false` if the block size conflicts with data already verified
+    /// or any of the blocks is beyond the end of the transfer.
+    pub(crate) fn add_block_hashes(
+        &mut self,
+        msg_length: u32,
+        block_size: u32,
+        first: u32,
+        hashes: &[u8],
+    ) -> bool {
+        if block_size == 0 {
+            return false;
+        }
+        let blocks = (msg_length as u64).div_ceil(block_size as u64);
+        let count = (hashes.len() / 32) as u64;
+        if count > 0 && first as u64 + count > blocks {
+            return false;
+        }
+        if self.block_size != block_size {
+            if self.fed > 0 {
+                return false;
+            }
+            self.block_size = block_size;
+            self.block_hashes.clear();
+        }
+        for (i, hash) in hashes.chunks_exact(32).enumerate() {
+            let mut buf = [0; 32];
+            buf.copy_from_slice(hash);
+            // can't overflow, the index was checked against the number of blocks
+            self.block_hashes.insert(first + i as u32, buf);
+        }
+        true
+    }
+    /// Feeds the next bytes of the transfer.
+    ///
+    /// If a block fails verification the state is rewound to the start of that block,
+    /// which is returned as the error. The caller should discard all data from there.
+    pub(crate) fn update(&mut self, mut bytes: &[u8]) -> Result<(), u32> {
+        while !bytes.is_empty() {
+            let take = if self.block_size == 0 {
+                bytes.len()
+            } else {
+                let in_block = self.fed % self.block_size;
+                bytes.len().min((self.block_size - in_block) as usize)
+            };
+            let (head, tail) = bytes.split_at(take);
+            self.hasher.update(head);
+            self.block.update(head);
+            self.fed += take as u32;
+            bytes = tail;
+            if self.block_size != 0 && self.fed.is_multiple_of(self.block_size) {
+                self.end_block()?;
+            }
+        }
+        Ok(())
+    }
+    /// Returns the hash of all data fed, checking the final partial block if it has a hash.
+    pub(crate) fn finish(&mut self) -> Result<[u8; 32], u32> {
+        if self.block_size != 0 && !self.fed.is_multiple_of(self.block_size) {
+            self.end_block()?;
+        }
+        Ok(self.hasher.finalize_reset().into())
+    }
+    fn end_block(&mut self) -> Result<(), u32> {
+        let digest: [u8; 32] = self.block.finalize_reset().into();
+        let idx = (self.fed - 1) / self.block_size;
+        match self.block_hashes.get(&idx) {
+            Some(expected) if *expected != digest => {
+                self.hasher = self.block_start.clone();
+                self.fed = idx * self.block_size;
+                Err(self.fed)
+            }
+            _ => {
+                self.block_start = self.hasher.clone();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn block_hashes(data: &[u8], block_size: usize) -> Vec<u8> {
+        data.chunks(block_size)
+            .flat_map(|b| Sha256::digest(b).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn incremental_hash() {
+        let data = data(1000);
+        let mut v = Verifier::default();
+        for piece in data.chunks(77) {
+            v.update(piece).unwrap();
+        }
+        let expected: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(v.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn blocks_verified() {
+        let data = data(1000);
+        let mut v = Verifier::default();
+        assert!(v.add_block_hashes(1000, 256, 0, &block_hashes(&data, 256)));
+        for piece in data.chunks(100) {
+            v.update(piece).unwrap();
+        }
+        let expected: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(v.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn corrupt_block_rewinds() {
+        let data = data(1000);
+        let mut v = Verifier::default();
+        v.add_block_hashes(1000, 256, 0, &block_hashes(&data, 256));
+        let mut bad = data.clone();
+        bad[300] ^= 1;
+        v.update(&bad[..256]).unwrap();
+        assert_eq!(v.update(&bad[256..600]), Err(256));
+        // the data is fed again from the start of the failed block
+        v.update(&data[256..]).unwrap();
+        let expected: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(v.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn corrupt_last_block() {
+        let data = data(1000);
+        let mut v = Verifier::default();
+        v.add_block_hashes(1000, 256, 0, &block_hashes(&data, 256));
+        let mut bad = data;
+        bad[999] ^= 1;
+        v.update(&bad).unwrap();
+        assert_eq!(v.finish(), Err(768));
+    }
+
+    #[test]
+    fn block_size_fixed_once_fed() {
+        let data = data(1000);
+        let mut v = Verifier::default();
+        assert!(!v.add_block_hashes(1000, 0, 0, &[]));
+        assert!(v.add_block_hashes(1000, 256, 0, &block_hashes(&data[..512], 256)));
+        v.update(&data[..10]).unwrap();
+        assert!(!v.add_block_hashes(1000, 128, 0, &block_hashes(&data, 128)));
+        assert!(v.add_block_hashes(1000, 256, 2, &block_hashes(&data[512..], 256)));
+        v.reset();
+        assert!(v.add_block_hashes(1000, 128, 0, &[]));
+    }
+
+    #[test]
+    fn blocks_beyond_end_rejected() {
+        let data = data(1000);
+        let hashes = block_hashes(&data, 256);
+        let mut v = Verifier::default();
+        // 1000 bytes are 4 blocks of 256
+        assert!(!v.add_block_hashes(1000, 256, 1, &hashes));
+        assert!(!v.add_block_hashes(1000, 256, u32::MAX, &hashes[..32]));
+        assert!(!v.add_block_hashes(u32::MAX, 1, u32::MAX - 1, &hashes[..64]));
+        assert!(v.block_hashes.is_empty());
+        assert!(v.add_block_hashes(1000, 256, 3, &hashes[96..]));
+        assert_eq!(v.block_hashes.len(), 1);
+    }
+}